@@ -0,0 +1,199 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::group_store::{GroupSnapshot, GroupStore, StateLimits};
+use crate::models::{DetectorStats, LogEntry};
+use crate::window::GroupKey;
+
+// Latest expected entry of a group and when the group falls silent without another one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastSeen {
+    time: DateTime<Utc>,
+    deadline: DateTime<Utc>,
+    log_entry: LogEntry,
+    // Set once the silence has been reported, until the group shows up again
+    reported: bool,
+}
+
+/// A group that went quiet: its last expected entry and the deadline it missed.
+#[derive(Debug)]
+pub struct Silence {
+    pub group: GroupKey,
+    pub last_seen: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+    pub log_entry: LogEntry,
+}
+
+/// Per-group state of one absence rule: each group must produce an expected entry at least every
+/// `interval`. Groups are tracked from their first expected entry on, and each silence is reported
+/// once.
+#[derive(Debug)]
+pub struct AbsenceTracker {
+    interval: Duration,
+    groups: GroupStore<LastSeen>,
+    // Unreported deadlines, earliest first
+    deadlines: BTreeSet<(DateTime<Utc>, GroupKey)>,
+}
+
+impl AbsenceTracker {
+    pub fn new(interval: Duration, limits: &StateLimits) -> Self {
+        // Silence is what this rule looks for, so a group only expires once it has been idle for
+        // longer than `interval`, i.e. after its silence was reported
+        let idle_timeout = limits.idle_timeout.map(|idle_timeout| idle_timeout.max(interval));
+        let groups = GroupStore::new(limits.max_keys, idle_timeout).collecting_dropped();
+        AbsenceTracker { interval, groups, deadlines: BTreeSet::new() }
+    }
+
+    /// Records an expected entry of `group` seen at `time`. Entries older than the group's latest
+    /// one are ignored.
+    pub fn seen(&mut self, group: GroupKey, time: DateTime<Utc>, log_entry: &LogEntry) {
+        if let Some(last) = self.groups.get_mut(&group) {
+            if last.time >= time {
+                return;
+            }
+            if !last.reported {
+                self.deadlines.remove(&(last.deadline, group.clone()));
+            }
+        }
+        let deadline = time + self.interval;
+        self.groups.insert(group.clone(), time, LastSeen { time, deadline, log_entry: log_entry.clone(), reported: false });
+        self.deadlines.insert((deadline, group));
+        self.forget_dropped();
+    }
+
+    /// Groups whose deadline passed before `now` and weren't reported yet. Each is returned once
+    /// per silence.
+    pub fn overdue(&mut self, now: DateTime<Utc>) -> Vec<Silence> {
+        let mut overdue = Vec::new();
+        while let Some((deadline, _)) = self.deadlines.first() {
+            if *deadline >= now {
+                break;
+            }
+            let Some((deadline, group)) = self.deadlines.pop_first() else { break };
+            if let Some(last) = self.groups.get_mut(&group) {
+                last.reported = true;
+                overdue.push(Silence { last_seen: last.time, deadline, log_entry: last.log_entry.clone(), group });
+            }
+        }
+        self.groups.expire_idle(now);
+        self.forget_dropped();
+        overdue
+    }
+
+    pub fn stats(&self) -> DetectorStats {
+        self.groups.stats()
+    }
+
+    pub fn snapshot(&self) -> Vec<GroupSnapshot<LastSeen>> {
+        self.groups.snapshot()
+    }
+
+    /// Re-adds saved groups; silences that began before the snapshot are still reported.
+    pub fn restore(&mut self, groups: Vec<GroupSnapshot<LastSeen>>) {
+        for group in groups.iter().filter(|group| !group.state.reported) {
+            self.deadlines.insert((group.state.deadline, group.key.clone()));
+        }
+        self.groups.restore(groups);
+        self.forget_dropped();
+    }
+
+    // Drops the deadlines of groups the store expired or evicted.
+    fn forget_dropped(&mut self) {
+        for (group, last) in self.groups.take_dropped() {
+            if !last.reported {
+                self.deadlines.remove(&(last.deadline, group));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules_engine::RulesEngine;
+    use crate::threat_detection::ThreatDetector;
+    use std::sync::Arc;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn group(name: &str) -> GroupKey {
+        vec![name.to_string()]
+    }
+
+    // Expects an entry every 60s
+    fn tracker() -> AbsenceTracker {
+        AbsenceTracker::new(Duration::seconds(60), &StateLimits::default())
+    }
+
+    fn seen(tracker: &mut AbsenceTracker, name: &str, t: i64) {
+        tracker.seen(group(name), at(t), &LogEntry::unparsed(&format!("{} {}", name, t)));
+    }
+
+    fn overdue(tracker: &mut AbsenceTracker, t: i64) -> Vec<(GroupKey, DateTime<Utc>, DateTime<Utc>)> {
+        tracker.overdue(at(t)).into_iter().map(|silence| (silence.group, silence.last_seen, silence.deadline)).collect()
+    }
+
+    #[test]
+    fn silence_is_reported_once_stamped_with_its_deadline() {
+        let mut tracker = tracker();
+        seen(&mut tracker, "a", 0);
+        assert!(overdue(&mut tracker, 60).is_empty());
+        assert_eq!(overdue(&mut tracker, 61), vec![(group("a"), at(0), at(60))]);
+        assert!(overdue(&mut tracker, 500).is_empty());
+    }
+
+    #[test]
+    fn a_late_heartbeat_re_arms_the_group() {
+        let mut tracker = tracker();
+        seen(&mut tracker, "a", 0);
+        assert_eq!(overdue(&mut tracker, 100).len(), 1);
+        seen(&mut tracker, "a", 90);
+        // Older than the group's latest entry, so the deadline stays at 150
+        seen(&mut tracker, "a", 80);
+        assert!(overdue(&mut tracker, 150).is_empty());
+        assert_eq!(overdue(&mut tracker, 151), vec![(group("a"), at(90), at(150))]);
+    }
+
+    #[test]
+    fn restored_groups_keep_their_deadlines_and_reported_silences() {
+        let mut tracker = tracker();
+        seen(&mut tracker, "a", 0);
+        seen(&mut tracker, "b", 30);
+        assert_eq!(overdue(&mut tracker, 61).len(), 1);
+
+        let mut restored = self::tracker();
+        restored.restore(tracker.snapshot());
+        assert_eq!(overdue(&mut restored, 91), vec![(group("b"), at(30), at(90))]);
+        assert_eq!(restored.stats().tracked_keys, 2);
+    }
+
+    #[test]
+    fn event_time_checks_stop_short_of_the_newest_entry_by_the_allowed_lateness() {
+        let rule = serde_json::from_value(serde_json::json!({
+            "id": "hb", "name": "Heartbeat", "pattern": "", "description": "", "alert_type": "SuspiciousIp", "enabled": true,
+            "rule_type": "Absence", "time_window_seconds": 60, "group_by": ["ip_address"], "allowed_lateness_seconds": 30,
+        }))
+        .unwrap();
+        let mut detector = ThreatDetector::new(Arc::new(RulesEngine::from_rules(vec![rule])));
+        let entry = |ip: &str, t: i64| LogEntry { ip_address: Some(ip.to_string()), timestamp: Some(at(t)), ..LogEntry::unparsed(ip) };
+
+        detector.detect_threats(&entry("a", 0));
+        detector.detect_threats(&entry("b", 0));
+        detector.detect_threats(&entry("b", 80));
+        // Within the lateness, so it still counts for `a` although `b` is already at 80
+        detector.detect_threats(&entry("a", 50));
+        detector.detect_threats(&entry("b", 130));
+        assert!(detector.take_pending_alerts().is_empty());
+
+        // `a`'s deadline is 50 + 60, so it's missed once the horizon, 30s behind `now`, is past 110
+        detector.check_absences(at(140));
+        assert!(detector.take_pending_alerts().is_empty());
+        detector.check_absences(at(141));
+        let alerts = detector.take_pending_alerts();
+        assert_eq!(alerts.iter().map(|alert| alert.timestamp).collect::<Vec<_>>(), vec![at(110)]);
+        assert_eq!(alerts[0].log_entry_sample.as_ref().and_then(|entry| entry.ip_address.as_deref()), Some("a"));
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, NaiveDate};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::log_processor::{parse_timestamp, set_entry_field};
+use crate::models::LogEntry;
+
+/// Apache/NCSA Common Log Format. `$remote_logname` is the identd name, almost always `-`.
+pub const COMMON_FORMAT: &str = r#"$remote_addr $remote_logname $remote_user [$time_local] "$request" $status $body_bytes_sent"#;
+
+/// Common Log Format plus referrer and user agent, as Nginx and Apache write by default.
+pub const COMBINED_FORMAT: &str = r#"$remote_addr $remote_logname $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+enum Token<'f> {
+    Literal(&'f str),
+    Variable(&'f str),
+}
+
+// Splits a format into literals and `$name` / `${name}` variables; a `$` not followed by a name is literal.
+fn tokenize(format: &str) -> Vec<Token<'_>> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut rest = format;
+    while let Some(dollar) = rest.find('$') {
+        let after = &rest[dollar + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some((name, remainder)) if !name.is_empty() && name.chars().all(is_name) => (name, remainder),
+                _ => ("", after),
+            },
+            None => {
+                let end = after.find(|c: char| !is_name(c)).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            },
+        };
+        if name.is_empty() {
+            tokens.push(Token::Literal(&rest[..=dollar]));
+        } else {
+            if dollar > 0 {
+                tokens.push(Token::Literal(&rest[..dollar]));
+            }
+            tokens.push(Token::Variable(name));
+        }
+        rest = remainder;
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest));
+    }
+    tokens
+}
+
+/// The anchored regex matching lines in `log_format`: `common`, `combined` or an Nginx
+/// `log_format` string. Each variable becomes a capture group named after it, matching up to the
+/// literal that follows; quoted values may contain backslash-escaped quotes.
+pub fn format_regex(log_format: &str) -> String {
+    let format = match log_format {
+        "common" => COMMON_FORMAT,
+        "combined" => COMBINED_FORMAT,
+        format => format,
+    };
+    let tokens = tokenize(format);
+    let mut named = HashSet::new();
+    let mut pattern = String::from("^");
+    for (index, token) in tokens.iter().enumerate() {
+        let name = match token {
+            Token::Literal(literal) => {
+                pattern.push_str(&regex::escape(literal));
+                continue;
+            },
+            Token::Variable(name) => name,
+        };
+        let value = match tokens.get(index + 1) {
+            None => ".*".to_string(),
+            Some(Token::Variable(_)) => ".*?".to_string(),
+            Some(Token::Literal(next)) => match next.chars().next() {
+                Some('"') => r#"(?:[^"\\]|\\.)*"#.to_string(),
+                Some(stop) => format!("[^{}]*", regex::escape(&stop.to_string())),
+                None => ".*?".to_string(),
+            },
+        };
+        // Group names can't start with a digit or repeat
+        if !name.starts_with(|c: char| c.is_ascii_digit()) && named.insert(*name) {
+            pattern.push_str(&format!("(?P<{}>{})", name, value));
+        } else {
+            pattern.push_str(&format!("(?:{})", value));
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+// "GET /search?q=x HTTP/1.1"; anything else (e.g. a TLS handshake sent to the HTTP port) only sets `message`.
+fn apply_request_line(entry: &mut LogEntry, request: &str) {
+    let mut parts = request.split(' ');
+    if let (Some(method), Some(target)) = (parts.next(), parts.next()) {
+        if !method.is_empty() && method.chars().all(|c| c.is_ascii_uppercase()) {
+            entry.method = Some(method.to_string());
+            apply_request_uri(entry, target);
+            if let Some(protocol) = parts.next() {
+                entry.extra.insert("protocol".to_string(), protocol.to_string());
+            }
+        }
+    }
+    entry.message = Some(request.to_string());
+}
+
+fn apply_request_uri(entry: &mut LogEntry, uri: &str) {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    entry.path = Some(path.to_string());
+    entry.query = query.filter(|query| !query.is_empty()).map(str::to_string);
+}
+
+/// Fills the entry from the variables an access log format captured. The request line sets
+/// `method`, `path`, `query` and `message`; `-` (the format's "no value") and empty values are
+/// skipped, and variables without a field of their own go to `extra`. `field_map` then copies
+/// any field by name into another, e.g. `"event_type": "status"`.
+pub fn apply_access_fields(entry: &mut LogEntry, field_map: &HashMap<String, String>, variables: Vec<(&str, &str)>, reference: NaiveDate) {
+    for (name, value) in variables {
+        if value.is_empty() || value == "-" {
+            continue;
+        }
+        let field_name = match name {
+            "request" => {
+                apply_request_line(entry, value);
+                continue;
+            },
+            "request_uri" => {
+                apply_request_uri(entry, value);
+                continue;
+            },
+            "time_local" | "time_iso8601" => {
+                entry.timestamp = parse_timestamp(value, reference);
+                continue;
+            },
+            "msec" => {
+                entry.timestamp = value.parse::<f64>().ok().and_then(|seconds| DateTime::from_timestamp_millis((seconds * 1000.0).round() as i64));
+                continue;
+            },
+            "remote_addr" => "ip_address",
+            "remote_user" => "user_id",
+            "request_method" => "method",
+            "uri" => "path",
+            "args" | "query_string" => "query",
+            "body_bytes_sent" | "bytes_sent" => "bytes",
+            "http_referer" => "referrer",
+            "http_user_agent" => "user_agent",
+            "server_protocol" => "protocol",
+            other => other,
+        };
+        set_entry_field(entry, field_name, value.to_string(), reference);
+    }
+
+    for (field_name, source) in field_map {
+        if let Some(value) = entry.field(source).map(Cow::into_owned) {
+            set_entry_field(entry, field_name, value, reference);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn variables(format: &str) -> Vec<String> {
+        tokenize(format)
+            .into_iter()
+            .filter_map(|token| match token {
+                Token::Variable(name) => Some(name.to_string()),
+                Token::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    fn parse(log_format: &str, line: &str) -> Option<LogEntry> {
+        let regex = Regex::new(&format_regex(log_format)).unwrap();
+        let captures = regex.captures(line)?;
+        let variables = regex.capture_names().flatten().filter_map(|name| captures.name(name).map(|value| (name, value.as_str()))).collect();
+        let mut entry = LogEntry::unparsed(line);
+        apply_access_fields(&mut entry, &HashMap::new(), variables, NaiveDate::from_ymd_opt(2026, 1, 1).unwrap());
+        Some(entry)
+    }
+
+    #[test]
+    fn tokenizes_plain_and_braced_variables() {
+        assert_eq!(variables("$remote_addr-${host}x $status"), vec!["remote_addr", "host", "status"]);
+        // `$` without a name, and an unterminated brace, stay literal
+        assert_eq!(variables("cost $ 5 ${oops $a"), vec!["a"]);
+        let tokens = tokenize("${host}x");
+        assert!(matches!(tokens.as_slice(), [Token::Variable("host"), Token::Literal("x")]));
+    }
+
+    #[test]
+    fn combined_line_fills_web_request_fields() {
+        let line = r#"10.0.0.9 - frank [17/Oct/2026:10:00:01 +0200] "GET /search?q=a%20b HTTP/1.1" 503 1234 "https://ex.com/?a=\"b\"" "Mozilla/5.0 (X11)""#;
+        let entry = parse("combined", line).unwrap();
+        assert_eq!((entry.ip_address.as_deref(), entry.user_id.as_deref()), (Some("10.0.0.9"), Some("frank")));
+        assert_eq!(entry.timestamp.map(|ts| ts.to_rfc3339()), Some("2026-10-17T08:00:01+00:00".to_string()));
+        assert_eq!((entry.method.as_deref(), entry.path.as_deref(), entry.query.as_deref()), (Some("GET"), Some("/search"), Some("q=a%20b")));
+        assert_eq!((entry.status, entry.bytes), (Some(503), Some(1234)));
+        assert_eq!(entry.referrer.as_deref(), Some(r#"https://ex.com/?a=\"b\""#));
+        assert_eq!(entry.user_agent.as_deref(), Some("Mozilla/5.0 (X11)"));
+        assert_eq!(entry.message.as_deref(), Some("GET /search?q=a%20b HTTP/1.1"));
+    }
+
+    #[test]
+    fn dashes_are_unset_and_bad_request_lines_only_set_message() {
+        let entry = parse("common", r#"10.0.0.9 - - [17/Oct/2026:10:00:02 +0000] "\x16\x03\x01" 400 -"#).unwrap();
+        assert_eq!((entry.user_id, entry.bytes, entry.status), (None, None, Some(400)));
+        assert_eq!((entry.method, entry.path), (None, None));
+        assert_eq!(entry.message.as_deref(), Some(r"\x16\x03\x01"));
+        assert!(entry.extra.is_empty());
+    }
+
+    #[test]
+    fn custom_format_keeps_unknown_variables_in_extra() {
+        let format = r#"$remote_addr ${host} "$request" $status $request_time up=$upstream_addr"#;
+        let entry = parse(format, r#"203.0.113.5 api.example.com "GET /v1/items HTTP/2.0" 200 0.123 up=10.1.1.1:80"#).unwrap();
+        assert_eq!(entry.extra.get("host").map(String::as_str), Some("api.example.com"));
+        assert_eq!(entry.extra.get("request_time").map(String::as_str), Some("0.123"));
+        assert_eq!(entry.extra.get("upstream_addr").map(String::as_str), Some("10.1.1.1:80"));
+        assert_eq!(entry.extra.get("protocol").map(String::as_str), Some("HTTP/2.0"));
+        // The format is anchored at both ends
+        assert!(parse(format, "203.0.113.5 api.example.com").is_none());
+    }
+}
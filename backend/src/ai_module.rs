@@ -74,6 +74,7 @@ pub fn generate_rule_from_description(description: &str) -> Result<Rule, String>
 }
 
 // Keep legacy functions if they are used elsewhere, but marked as candidates for removal
+#[allow(dead_code)]
 pub fn explain_metrics(_metrics: &Vec<Metrics>) -> AiExplanation {
     AiExplanation {
         explanation: "Legacy metrics explanation is being replaced by per-alert explanation.".to_string(),
@@ -0,0 +1,106 @@
+use crate::models::{LogEntry, WorkerMessage};
+use crate::rules_engine::RulesEngine;
+use crate::log_processor::{process_sequential, process_parallel};
+use crate::distributed_analysis::protocol::{decode_frame, encode_frame, Encoding, FrameCodec};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct BenchmarkReport {
+    pub total_logs: usize,
+    pub iterations: usize,
+    pub threads: usize,
+    pub sequential_ms: f64,
+    pub parallel_ms: f64,
+    pub alerts_match: bool,
+}
+
+impl BenchmarkReport {
+    pub fn speedup(&self) -> f64 {
+        if self.parallel_ms > 0.0 { self.sequential_ms / self.parallel_ms } else { 0.0 }
+    }
+}
+
+fn median(mut samples: Vec<f64>) -> f64 {
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    samples[samples.len() / 2]
+}
+
+/// Times `process_sequential` against `process_parallel` on the same entries and rules snapshot,
+/// reporting the median of `iterations` runs for each and whether both produced the same alert counts per rule.
+pub fn run_benchmark(log_entries: Vec<LogEntry>, rules_engine: Arc<RulesEngine>, iterations: usize, partition_key: &str) -> BenchmarkReport {
+    let iterations = iterations.max(1);
+    let mut sequential_samples = Vec::with_capacity(iterations);
+    let mut parallel_samples = Vec::with_capacity(iterations);
+    let mut alerts_match = true;
+
+    for _ in 0..iterations {
+        let sequential = process_sequential(log_entries.clone(), Arc::clone(&rules_engine));
+        let parallel = process_parallel(log_entries.clone(), Arc::clone(&rules_engine), partition_key);
+        alerts_match &= sequential.alerts_by_rule == parallel.alerts_by_rule;
+        sequential_samples.push(sequential.execution_time_ms);
+        parallel_samples.push(parallel.execution_time_ms);
+    }
+
+    BenchmarkReport {
+        total_logs: log_entries.len(),
+        iterations,
+        threads: rayon::current_num_threads(),
+        sequential_ms: median(sequential_samples),
+        parallel_ms: median(parallel_samples),
+        alerts_match,
+    }
+}
+
+#[derive(Debug)]
+pub struct CodecReport {
+    pub codec: FrameCodec,
+    pub frame_bytes: usize,
+    pub encode_ms: f64,
+    pub decode_ms: f64,
+}
+
+impl CodecReport {
+    /// Round-trip throughput in MB of JSON-equivalent log data per second.
+    pub fn throughput_mb_per_sec(&self, reference_bytes: usize) -> f64 {
+        let total_ms = self.encode_ms + self.decode_ms;
+        if total_ms > 0.0 { (reference_bytes as f64 / 1_000_000.0) / (total_ms / 1000.0) } else { 0.0 }
+    }
+}
+
+/// Frames the entries as one `WorkerMessage::LogChunk` with every codec, reporting the frame size
+/// and the median encode/decode time over `iterations` runs. The first report is the plain JSON baseline.
+pub fn run_codec_benchmark(log_entries: Vec<LogEntry>, iterations: usize) -> Result<Vec<CodecReport>, Box<dyn std::error::Error + Send + Sync>> {
+    let iterations = iterations.max(1);
+    let message = WorkerMessage::LogChunk { chunk_id: 0, entries: log_entries, watermark: None };
+    let codecs = [
+        FrameCodec { encoding: Encoding::Json, compression: false },
+        FrameCodec { encoding: Encoding::Json, compression: true },
+        FrameCodec { encoding: Encoding::MessagePack, compression: false },
+        FrameCodec { encoding: Encoding::MessagePack, compression: true },
+    ];
+
+    let mut reports = Vec::new();
+    for codec in codecs {
+        let mut encode_samples = Vec::with_capacity(iterations);
+        let mut decode_samples = Vec::with_capacity(iterations);
+        let mut frame_bytes = 0;
+        for _ in 0..iterations {
+            let start = Instant::now();
+            let frame = encode_frame(&message, codec)?;
+            encode_samples.push(start.elapsed().as_secs_f64() * 1000.0);
+            frame_bytes = frame.len();
+
+            let start = Instant::now();
+            let _: WorkerMessage = decode_frame(&frame)?;
+            decode_samples.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        reports.push(CodecReport {
+            codec,
+            frame_bytes,
+            encode_ms: median(encode_samples),
+            decode_ms: median(decode_samples),
+        });
+    }
+    Ok(reports)
+}
@@ -1,117 +1,463 @@
-use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::models::{LogEntry, Alert, Metrics, Rule, WorkerMessage, MasterMessage};
-use std::time::Instant;
-use chrono::{Utc};
+use chrono::{DateTime, Utc};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use crate::log_processor::parse_log_line;
+use crate::models::{LogEntry, Alert, Metrics, ParsingRule, Rule, WorkerCapabilities, WorkerMessage, MasterMessage};
+use crate::parser_config::ParserSet;
+use crate::partition::{partition_by_key, partition_index, split_by_coverage, DEFAULT_PARTITION_KEY};
+use crate::rules_engine::RulesEngine;
+use crate::sequential_analysis::run_sequential_analysis;
+use super::protocol::{read_message, read_message_within, write_message, Encoding, FrameCodec, ProtocolResult, MAX_HANDSHAKE_FRAME_SIZE, PROTOCOL_VERSION};
+use super::progress::RunProgress;
+use super::security::{new_nonce, ClusterSecurity, ClusterStream};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const WORKER_PORT: u16 = 8081;
+/// Scheduling knobs for a distributed run.
+#[derive(Debug, Clone)]
+pub struct MasterConfig {
+    /// Target entries per work unit. Workers pull units one at a time, so smaller units balance better.
+    pub unit_size: usize,
+    /// Field that routes entries to work units; every entry with the same value lands in the same unit.
+    pub partition_key: String,
+    /// A worker that sends neither a heartbeat nor a result for this long is considered dead.
+    pub heartbeat_timeout: Duration,
+    /// Upper bound on a single unit, heartbeats or not.
+    pub chunk_timeout: Duration,
+    /// Attempts per unit before the whole run is failed.
+    pub max_attempts: usize,
+    /// Preferred wire encoding; workers that don't support it fall back to JSON.
+    pub encoding: Encoding,
+    /// Deflate log chunks and results on the wire.
+    pub compression: bool,
+    /// TLS and shared-secret settings for worker connections.
+    pub security: Arc<ClusterSecurity>,
+    /// Live progress of the run, updated as workers stream it.
+    pub progress: Arc<RunProgress>,
+}
 
-pub async fn run_master(log_lines: Vec<String>, rules: Vec<Rule>, num_workers: usize) -> Result<Metrics, Box<dyn std::error::Error + Send + Sync>> {
-    let start_time = Instant::now();
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", WORKER_PORT)).await?;
-    println!("Master listening on port {}", WORKER_PORT);
+impl Default for MasterConfig {
+    fn default() -> Self {
+        MasterConfig {
+            unit_size: 5_000,
+            partition_key: DEFAULT_PARTITION_KEY.to_string(),
+            heartbeat_timeout: Duration::from_secs(10),
+            chunk_timeout: Duration::from_secs(300),
+            max_attempts: 3,
+            encoding: Encoding::MessagePack,
+            compression: true,
+            security: Arc::new(ClusterSecurity::default()),
+            progress: Arc::new(RunProgress::default()),
+        }
+    }
+}
 
-    let mut worker_streams = Vec::new();
+/// What a distributed run analyzes.
+#[derive(Clone)]
+pub enum JobInput {
+    /// Entries that were already parsed, e.g. posted to the API.
+    Entries(Vec<LogEntry>),
+    /// Raw lines; workers parse them with `parsing_rules` exactly as `parse_log_content` would.
+    Lines { lines: Vec<String>, parsing_rules: Vec<ParsingRule> },
+}
 
-    for i in 0..num_workers {
-        println!("Waiting for worker {}", i + 1);
-        let (stream, _) = listener.accept().await?;
-        worker_streams.push(stream);
-        println!("Worker {} connected", i + 1);
+#[derive(Clone)]
+enum UnitPayload {
+    Entries(Vec<LogEntry>),
+    Lines(Vec<String>),
+}
+
+impl UnitPayload {
+    fn len(&self) -> usize {
+        match self {
+            UnitPayload::Entries(entries) => entries.len(),
+            UnitPayload::Lines(lines) => lines.len(),
+        }
     }
 
-    // Send rules to all workers
-    for stream in &mut worker_streams {
-        let rules_message = WorkerMessage::Rules(rules.clone());
-        let serialized_rules = serde_json::to_vec(&rules_message)?;
-        stream.write_all(&(serialized_rules.len() as u32).to_le_bytes()).await?;
-        stream.write_all(&serialized_rules).await?;
+    fn into_message(self, chunk_id: usize, watermark: Option<DateTime<Utc>>) -> WorkerMessage {
+        match self {
+            UnitPayload::Entries(entries) => WorkerMessage::LogChunk { chunk_id, entries, watermark },
+            UnitPayload::Lines(lines) => WorkerMessage::RawLogChunk { chunk_id, lines, watermark },
+        }
     }
+}
 
-    let chunk_size = (log_lines.len() + num_workers - 1) / num_workers;
-    let mut all_alerts: Vec<Alert> = Vec::new();
-    let mut total_processed_logs = 0;
+// Partitions the input into non-empty units, and finds the job's newest timestamp. Raw lines are
+// routed on the captured key field and timestamp alone; full parsing happens on the workers.
+fn partition_input(input: JobInput, config: &MasterConfig) -> (Vec<UnitPayload>, Option<Vec<ParsingRule>>, Option<DateTime<Utc>>) {
+    match input {
+        JobInput::Entries(entries) => {
+            let watermark = entries.iter().filter_map(|entry| entry.timestamp).max();
+            let num_partitions = entries.len().div_ceil(config.unit_size.max(1));
+            let payloads = partition_by_key(entries, &config.partition_key, num_partitions)
+                .into_iter()
+                .filter(|partition| !partition.is_empty())
+                .map(UnitPayload::Entries)
+                .collect();
+            (payloads, None, watermark)
+        },
+        JobInput::Lines { lines, parsing_rules } => {
+            let parsers = ParserSet::new(parsing_rules.clone());
+            let num_partitions = lines.len().div_ceil(config.unit_size.max(1)).max(1);
+            let mut partitions: Vec<Vec<String>> = (0..num_partitions).map(|_| Vec::new()).collect();
+            let mut watermark = None;
+            for line in lines {
+                let (key, time) = parsers.extract_field_and_time(&line, &config.partition_key);
+                let partition = partition_index(key.as_deref(), num_partitions);
+                watermark = watermark.max(time);
+                partitions[partition].push(line);
+            }
+            let payloads = partitions
+                .into_iter()
+                .filter(|partition| !partition.is_empty())
+                .map(UnitPayload::Lines)
+                .collect();
+            (payloads, Some(parsing_rules), watermark)
+        },
+    }
+}
+
+// Stateful rules that don't group by the partition key need every entry, so the master runs them
+// itself over the whole input while the workers analyze their units.
+fn spawn_unsharded(input: JobInput, rules: Vec<Rule>) -> tokio::task::JoinHandle<Metrics> {
+    tokio::task::spawn_blocking(move || {
+        let entries = match input {
+            JobInput::Entries(entries) => entries,
+            JobInput::Lines { lines, parsing_rules } => {
+                let parsers = ParserSet::new(parsing_rules);
+                lines.iter().map(|line| parse_log_line(line, &parsers)).collect()
+            },
+        };
+        run_sequential_analysis(entries, Arc::new(RulesEngine::from_rules(rules)))
+    })
+}
+
+/// An open, handshaken connection to one worker.
+pub struct WorkerSession {
+    stream: Box<dyn ClusterStream>,
+    address: String,
+    codec: FrameCodec,
+    // Longest wait for the reply to a request other than a chunk
+    reply_timeout: Duration,
+    pub capabilities: WorkerCapabilities,
+}
+
+impl WorkerSession {
+    /// Connects and handshakes, giving up after `config.heartbeat_timeout`.
+    pub async fn connect(address: &str, config: &MasterConfig) -> ProtocolResult<Self> {
+        timeout(config.heartbeat_timeout, Self::handshake(address, config)).await
+            .map_err(|_| format!("Worker {} did not complete the handshake within {:?}", address, config.heartbeat_timeout))?
+    }
+
+    async fn handshake(address: &str, config: &MasterConfig) -> ProtocolResult<Self> {
+        let security = &config.security;
+        let mut stream = security.connect(address).await?;
+
+        let mut encodings = vec![config.encoding];
+        if config.encoding != Encoding::Json {
+            encodings.push(Encoding::Json);
+        }
+        let master_nonce = new_nonce();
+        let hello = WorkerMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            encodings,
+            compression: config.compression,
+            nonce: master_nonce.clone(),
+        };
+        write_message(&mut stream, &hello, FrameCodec::HANDSHAKE).await?;
+        let (capabilities, codec, worker_nonce, proof) = match read_message_within::<_, MasterMessage>(&mut stream, MAX_HANDSHAKE_FRAME_SIZE).await? {
+            MasterMessage::Hello { protocol_version, capabilities, codec, nonce, proof } if protocol_version == PROTOCOL_VERSION => {
+                (capabilities, codec, nonce, proof)
+            },
+            MasterMessage::Hello { protocol_version, .. } => return Err(format!(
+                "Worker {} speaks protocol version {}, expected {}", address, protocol_version, PROTOCOL_VERSION
+            ).into()),
+            MasterMessage::Error(e) => return Err(format!("Worker {} refused handshake: {}", address, e).into()),
+            other => return Err(format!("Unexpected handshake reply from worker {}: {:?}", address, other).into()),
+        };
+
+        let mut session = WorkerSession { stream, address: address.to_string(), codec, reply_timeout: config.heartbeat_timeout, capabilities };
+        match (security.has_shared_secret(), proof) {
+            (false, None) => {},
+            (false, Some(_)) => return Err(format!("Worker {} requires a shared secret", address).into()),
+            (true, None) => return Err(format!("Worker {} did not prove the shared secret", address).into()),
+            (true, Some(proof)) => {
+                if !security.verify_proof("worker", &master_nonce, &worker_nonce, &proof) {
+                    return Err(format!("Worker {} failed shared-secret authentication", address).into());
+                }
+                let proof = security.session_proof("master", &master_nonce, &worker_nonce).unwrap_or_default();
+                session.expect_ack(&WorkerMessage::Authenticate { proof }, "authentication").await?;
+            },
+        }
+        Ok(session)
+    }
+
+    // Sends one request and reads its reply within `reply_timeout`, turning an error frame into an `Err`.
+    async fn request(&mut self, message: &WorkerMessage) -> ProtocolResult<MasterMessage> {
+        let exchange = async {
+            write_message(&mut self.stream, message, self.codec).await?;
+            read_message::<_, MasterMessage>(&mut self.stream).await
+        };
+        let reply = timeout(self.reply_timeout, exchange).await
+            .map_err(|_| format!("Worker {} did not reply within {:?}", self.address, self.reply_timeout))??;
+        match reply {
+            MasterMessage::Error(e) => Err(format!("Worker {} returned an error: {}", self.address, e).into()),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn expect_ack(&mut self, message: &WorkerMessage, what: &str) -> ProtocolResult<()> {
+        match self.request(message).await? {
+            MasterMessage::Ack => Ok(()),
+            other => Err(format!("Unexpected reply from worker {} to {}: {:?}", self.address, what, other).into()),
+        }
+    }
+
+    pub async fn send_rules(&mut self, rules: Vec<Rule>) -> ProtocolResult<()> {
+        self.expect_ack(&WorkerMessage::Rules(rules), "rules").await
+    }
+
+    pub async fn send_parsing_rules(&mut self, parsing_rules: Vec<ParsingRule>) -> ProtocolResult<()> {
+        self.expect_ack(&WorkerMessage::ParsingRules(parsing_rules), "parsing rules").await
+    }
+
+    /// Sends one chunk and waits for its result, recording progress frames in `config.progress`.
+    /// Progress keeps the wait alive up to `chunk_timeout`; silence for longer than
+    /// `heartbeat_timeout` fails the chunk.
+    async fn analyze_chunk(&mut self, chunk_id: usize, payload: UnitPayload, watermark: Option<DateTime<Utc>>, config: &MasterConfig) -> ProtocolResult<Metrics> {
+        timeout(config.heartbeat_timeout, write_message(&mut self.stream, &payload.into_message(chunk_id, watermark), self.codec)).await
+            .map_err(|_| format!("Worker {} stopped accepting chunk {}", self.address, chunk_id))??;
+
+        let deadline = Instant::now() + config.chunk_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(format!("Worker {} did not finish chunk {} within {:?}", self.address, chunk_id, config.chunk_timeout).into());
+            }
+            let reply = timeout(config.heartbeat_timeout.min(remaining), read_message::<_, MasterMessage>(&mut self.stream)).await
+                .map_err(|_| format!("Worker {} went silent while processing chunk {}", self.address, chunk_id))??;
+
+            match reply {
+                MasterMessage::Progress(progress) if progress.chunk_id == chunk_id => {
+                    config.progress.chunk_progress(&self.address, &progress);
+                },
+                MasterMessage::AnalysisResult(metrics) => return Ok(metrics),
+                MasterMessage::Error(e) => return Err(format!("Worker {} failed chunk {}: {}", self.address, chunk_id, e).into()),
+                other => return Err(format!("Unexpected reply from worker {} to chunk {}: {:?}", self.address, chunk_id, other).into()),
+            }
+        }
+    }
+
+    /// Ends the session.
+    pub async fn finish(mut self) -> ProtocolResult<()> {
+        self.expect_ack(&WorkerMessage::Shutdown, "shutdown").await
+    }
+}
+
+struct WorkUnit {
+    id: usize,
+    payload: UnitPayload,
+    attempts: usize,
+}
 
-    let mut handles = Vec::new();
-
-    for (i, mut stream) in worker_streams.into_iter().enumerate() {
-        let start_index = i * chunk_size;
-        let end_index = (start_index + chunk_size).min(log_lines.len());
-        let chunk = log_lines[start_index..end_index].to_vec();
-
-        let handle = tokio::spawn(async move {
-            let log_entries_chunk: Vec<LogEntry> = chunk.into_iter().map(|line| LogEntry {
-                timestamp: Some(Utc::now()), // Assign current UTC timestamp
-                ip_address: None,
-                user_id: None,
-                raw_log: line.to_string(),
-                event_type: None,
-                level: None,
-                message: None,
-                extra: std::collections::HashMap::new(),
-            }).collect();
-            let log_chunk_message = WorkerMessage::LogChunk(log_entries_chunk);
-            let serialized_chunk = serde_json::to_vec(&log_chunk_message)?;
-            stream.write_all(&(serialized_chunk.len() as u32).to_le_bytes()).await?;
-            stream.write_all(&serialized_chunk).await?;
-
-            // Read ACK response from worker for LogChunk
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes).await?;
-            let len = u32::from_le_bytes(len_bytes) as usize;
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer).await?;
-            let worker_ack: MasterMessage = serde_json::from_slice(&buffer)?;
-            match worker_ack {
-                MasterMessage::Ack => println!("Worker acknowledged log chunk."),
-                _ => return Err(Box::<dyn std::error::Error + Send + Sync>::from("Unexpected worker response after log chunk.")),
+// Work queue shared by all worker tasks. Units are pulled on demand, and a unit whose
+// worker fails goes back to the front of the queue for the next healthy worker. Each unit is
+// a whole key partition, so it can be analyzed (or re-analyzed) independently of the others.
+struct Scheduler {
+    queue: Mutex<VecDeque<WorkUnit>>,
+    results: Mutex<Vec<Metrics>>,
+    completed: AtomicUsize,
+    total_units: usize,
+    failed: Mutex<Option<String>>,
+    // Newest timestamp in the job, sent with every unit
+    watermark: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    fn is_finished(&self) -> bool {
+        self.completed.load(Ordering::SeqCst) == self.total_units || self.failed.lock().unwrap().is_some()
+    }
+
+    // Waits for a unit; `None` once every unit is done (or the run has failed).
+    async fn next_unit(&self) -> Option<WorkUnit> {
+        loop {
+            if self.is_finished() {
+                return None;
+            }
+            if let Some(unit) = self.queue.lock().unwrap().pop_front() {
+                return Some(unit);
             }
+            // Other workers still hold units that may come back if they fail
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn requeue(&self, mut unit: WorkUnit, max_attempts: usize) {
+        unit.attempts += 1;
+        if unit.attempts >= max_attempts {
+            *self.failed.lock().unwrap() = Some(format!("Work unit {} failed on {} attempts", unit.id, unit.attempts));
+        } else {
+            self.queue.lock().unwrap().push_front(unit);
+        }
+    }
+
+    fn complete(&self, metrics: Metrics) {
+        self.results.lock().unwrap().push(metrics);
+        self.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// Connects to a worker and ships the rules its units need.
+async fn open_session(address: &str, rules: Vec<Rule>, parsing_rules: Option<Vec<ParsingRule>>, config: &MasterConfig) -> ProtocolResult<WorkerSession> {
+    let mut session = WorkerSession::connect(address, config).await?;
+    session.send_rules(rules).await?;
+    if let Some(parsing_rules) = parsing_rules {
+        session.send_parsing_rules(parsing_rules).await?;
+    }
+    Ok(session)
+}
+
+// Pulls units until the queue drains. Any failure hands the current unit back and retires the session.
+async fn run_session_loop(mut session: WorkerSession, scheduler: Arc<Scheduler>, config: MasterConfig) {
+    while let Some(unit) = scheduler.next_unit().await {
+        match session.analyze_chunk(unit.id, unit.payload.clone(), scheduler.watermark, &config).await {
+            Ok(metrics) => {
+                config.progress.chunk_completed(&session.address, unit.id, &metrics);
+                scheduler.complete(metrics);
+            },
+            Err(e) => {
+                config.progress.chunk_failed(&session.address, unit.id);
+                eprintln!("Reassigning work unit {} after failure: {}", unit.id, e);
+                scheduler.requeue(unit, config.max_attempts);
+                return;
+            }
+        }
+    }
 
-            // Store the stream to send shutdown later
-            Ok(stream)
-        }) as tokio::task::JoinHandle<Result<tokio::net::TcpStream, Box<dyn std::error::Error + Send + Sync>>>;
-        handles.push(handle);
-    }
-
-    let mut worker_streams_for_shutdown = Vec::new();
-    for handle in handles {
-        let stream_result = handle.await?;
-        let stream = stream_result?;
-        worker_streams_for_shutdown.push(stream);
-    }
-
-    // Now send shutdown messages and collect final results
-    let mut final_handles = Vec::new();
-    for mut stream in worker_streams_for_shutdown {
-        let handle: tokio::task::JoinHandle<Result<MasterMessage, Box<dyn std::error::Error + Send + Sync>>> = tokio::spawn(async move {
-            let shutdown_message = WorkerMessage::Shutdown;
-            let serialized_shutdown = serde_json::to_vec(&shutdown_message)?;
-            stream.write_all(&(serialized_shutdown.len() as u32).to_le_bytes()).await?;
-            stream.write_all(&serialized_shutdown).await?;
-
-            // Read final analysis result from worker
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes).await?;
-            let len = u32::from_le_bytes(len_bytes) as usize;
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer).await?;
-            let worker_result: MasterMessage = serde_json::from_slice(&buffer)?;
-
-            Ok(worker_result)
+    let address = session.address.clone();
+    if let Err(e) = session.finish().await {
+        eprintln!("Worker {} did not close cleanly: {}", address, e);
+    }
+}
+
+// Opens as many sessions as the worker's advertised capacity (and the number of units) allows,
+// each pulling units independently.
+async fn run_worker_loop(address: String, rules: Vec<Rule>, parsing_rules: Option<Vec<ParsingRule>>, scheduler: Arc<Scheduler>, config: MasterConfig) {
+    let session = match open_session(&address, rules.clone(), parsing_rules.clone(), &config).await {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Skipping worker {}: {}", address, e);
+            return;
+        }
+    };
+    config.progress.worker_connected(&address, session.capabilities.worker_id);
+    let capacity = session.capabilities.capacity.clamp(1, scheduler.total_units.max(1));
+    println!(
+        "Connected to worker {} at {} ({} cores, {} sessions, {:?})",
+        session.capabilities.worker_id, address, session.capabilities.cores, capacity, session.codec
+    );
+
+    let mut sessions = JoinSet::new();
+    for _ in 1..capacity {
+        let (address, rules, parsing_rules, scheduler, config) =
+            (address.clone(), rules.clone(), parsing_rules.clone(), Arc::clone(&scheduler), config.clone());
+        sessions.spawn(async move {
+            match open_session(&address, rules, parsing_rules, &config).await {
+                Ok(session) => run_session_loop(session, scheduler, config).await,
+                Err(e) => eprintln!("Could not open another session to worker {}: {}", address, e),
+            }
         });
-        final_handles.push(handle);
     }
+    run_session_loop(session, scheduler, config).await;
+    while sessions.join_next().await.is_some() {}
+}
+
+/// Partitions the entries by `config.partition_key` into work units that the workers at `worker_addresses` pull on demand,
+/// reassigning the units of workers that fail or time out, and merges the returned `Metrics`. Stateful rules
+/// that don't group by the partition key run on the master instead. Fails only if no worker can complete some unit.
+pub async fn run_master(input: JobInput, rules: Vec<Rule>, worker_addresses: Vec<String>, config: MasterConfig) -> ProtocolResult<Metrics> {
+    let progress = Arc::clone(&config.progress);
+    let result = schedule_units(input, rules, worker_addresses, config).await;
+    progress.finish(result.as_ref().err().map(|e| e.to_string()));
+    result
+}
+
+async fn schedule_units(input: JobInput, rules: Vec<Rule>, worker_addresses: Vec<String>, config: MasterConfig) -> ProtocolResult<Metrics> {
+    if worker_addresses.is_empty() {
+        return Err("No workers configured for distributed analysis.".into());
+    }
+    let start_time = Instant::now();
 
-    for handle in final_handles {
-        match handle.await? {
-            Ok(MasterMessage::AnalysisResult(metrics)) => {
-                all_alerts.extend(metrics.alerts_generated);
-                total_processed_logs += metrics.total_logs_processed;
+    let (rules, unsharded_rules) = split_by_coverage(&rules, &config.partition_key);
+    let unsharded = (!unsharded_rules.is_empty()).then(|| {
+        let ids: Vec<&str> = unsharded_rules.iter().map(|rule| rule.id.as_str()).collect();
+        println!("Running rules {} on the master: they don't group by partition key {}", ids.join(", "), config.partition_key);
+        spawn_unsharded(input.clone(), unsharded_rules)
+    });
+
+    let (payloads, parsing_rules, watermark) = partition_input(input, &config);
+    config.progress.start(payloads.len(), payloads.iter().map(UnitPayload::len).sum());
+    let queue: VecDeque<WorkUnit> = payloads
+        .into_iter()
+        .enumerate()
+        .map(|(id, payload)| WorkUnit { id, payload, attempts: 0 })
+        .collect();
+    println!("Scheduling {} work units across {} workers", queue.len(), worker_addresses.len());
+
+    let scheduler = Arc::new(Scheduler {
+        total_units: queue.len(),
+        queue: Mutex::new(queue),
+        results: Mutex::new(Vec::new()),
+        completed: AtomicUsize::new(0),
+        failed: Mutex::new(None),
+        watermark,
+    });
+
+    let mut loops = JoinSet::new();
+    for address in worker_addresses {
+        loops.spawn(run_worker_loop(address, rules.clone(), parsing_rules.clone(), Arc::clone(&scheduler), config.clone()));
+    }
+    // Once every unit is done, loops still running are only closing sessions or connecting to
+    // workers that have nothing left to do; give them a heartbeat to finish, then drop them.
+    while !scheduler.is_finished() {
+        tokio::select! {
+            joined = loops.join_next() => match joined {
+                Some(result) => result?,
+                None => break,
             },
-            Ok(MasterMessage::Error(e)) => eprintln!("Worker error during shutdown: {}", e),
-            _ => eprintln!("Unexpected worker response during shutdown."),
+            _ = tokio::time::sleep(Duration::from_millis(50)) => {},
         }
     }
+    let _ = timeout(config.heartbeat_timeout, async { while loops.join_next().await.is_some() {} }).await;
+    loops.abort_all();
+
+    if let Some(e) = scheduler.failed.lock().unwrap().take() {
+        return Err(e.into());
+    }
+    let completed = scheduler.completed.load(Ordering::SeqCst);
+    if completed < scheduler.total_units {
+        return Err(format!("Only {} of {} work units completed; no healthy workers remain", completed, scheduler.total_units).into());
+    }
+
+    let mut all_alerts: Vec<Alert> = Vec::new();
+    let mut total_processed_logs = 0;
+    let (mut late_entries, mut untimestamped_entries) = (0, 0);
+    if let Some(unsharded) = unsharded {
+        let metrics = unsharded.await?;
+        // The workers count the entries too
+        all_alerts.extend(metrics.alerts_generated);
+        late_entries += metrics.late_entries;
+        untimestamped_entries += metrics.untimestamped_entries;
+    }
+    for metrics in scheduler.results.lock().unwrap().drain(..) {
+        all_alerts.extend(metrics.alerts_generated);
+        total_processed_logs += metrics.total_logs_processed;
+        late_entries += metrics.late_entries;
+        untimestamped_entries += metrics.untimestamped_entries;
+    }
 
     let elapsed_time = start_time.elapsed();
     let execution_time_ms = elapsed_time.as_secs_f64() * 1000.0;
@@ -125,7 +471,11 @@ pub async fn run_master(log_lines: Vec<String>, rules: Vec<Rule>, num_workers: u
         total_logs_processed: total_processed_logs,
         execution_time_ms,
         logs_per_second,
+        alerts_by_rule: Metrics::count_alerts_by_rule(&all_alerts),
         alerts_generated: all_alerts,
+        late_entries,
+        untimestamped_entries,
         mode: "Distributed".to_string(),
+        worker_breakdown: config.progress.worker_breakdown(),
     })
 }
@@ -1,2 +1,6 @@
 pub mod master;
+pub mod progress;
+pub mod protocol;
+pub mod registry;
+pub mod security;
 pub mod worker;
@@ -0,0 +1,166 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::models::{Alert, ChunkProgress, Metrics, WorkerMetrics};
+
+/// Partial alerts kept for the live view; the final `Metrics` always has all of them.
+const MAX_RECENT_ALERTS: usize = 100;
+
+/// A point-in-time view of a distributed run, for the web UI.
+#[derive(Debug, Serialize, Clone)]
+pub struct ProgressSnapshot {
+    pub running: bool,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub elapsed_ms: f64,
+    pub total_units: usize,
+    pub completed_units: usize,
+    pub total_logs: usize,
+    /// Completed units plus whatever the workers have reported for the units in flight.
+    pub logs_processed: usize,
+    pub alerts_so_far: usize,
+    /// Most recent partial alerts, oldest first. Alerts from a unit that later fails stay listed
+    /// here but are not counted twice once the unit is reassigned.
+    pub recent_alerts: Vec<Alert>,
+    pub workers: Vec<WorkerMetrics>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct InFlight {
+    logs_processed: usize,
+    alerts_so_far: usize,
+    alerts_streamed: usize,
+}
+
+#[derive(Debug)]
+struct ProgressState {
+    started_at: DateTime<Utc>,
+    start: Instant,
+    finished_after_ms: Option<f64>,
+    error: Option<String>,
+    total_units: usize,
+    total_logs: usize,
+    workers: BTreeMap<String, WorkerMetrics>,
+    in_flight: HashMap<(String, usize), InFlight>,
+    recent_alerts: VecDeque<Alert>,
+}
+
+impl ProgressState {
+    fn push_recent<'a>(&mut self, alerts: impl IntoIterator<Item = &'a Alert>) {
+        for alert in alerts {
+            if self.recent_alerts.len() == MAX_RECENT_ALERTS {
+                self.recent_alerts.pop_front();
+            }
+            self.recent_alerts.push_back(alert.clone());
+        }
+    }
+}
+
+/// Live state of one distributed run, updated by the master as workers stream progress.
+#[derive(Debug)]
+pub struct RunProgress {
+    state: Mutex<ProgressState>,
+}
+
+impl Default for RunProgress {
+    fn default() -> Self {
+        RunProgress {
+            state: Mutex::new(ProgressState {
+                started_at: Utc::now(),
+                start: Instant::now(),
+                finished_after_ms: None,
+                error: None,
+                total_units: 0,
+                total_logs: 0,
+                workers: BTreeMap::new(),
+                in_flight: HashMap::new(),
+                recent_alerts: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl RunProgress {
+    pub(super) fn start(&self, total_units: usize, total_logs: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.started_at = Utc::now();
+        state.start = Instant::now();
+        state.total_units = total_units;
+        state.total_logs = total_logs;
+    }
+
+    pub(super) fn worker_connected(&self, address: &str, worker_id: usize) {
+        let mut state = self.state.lock().unwrap();
+        let worker = state.workers.entry(address.to_string()).or_default();
+        worker.worker_id = worker_id;
+        worker.address = address.to_string();
+    }
+
+    pub(super) fn chunk_progress(&self, address: &str, progress: &ChunkProgress) {
+        let mut state = self.state.lock().unwrap();
+        let in_flight = state.in_flight.entry((address.to_string(), progress.chunk_id)).or_default();
+        in_flight.logs_processed = progress.logs_processed;
+        in_flight.alerts_so_far = progress.alerts_so_far;
+        in_flight.alerts_streamed += progress.new_alerts.len();
+        state.push_recent(&progress.new_alerts);
+    }
+
+    pub(super) fn chunk_completed(&self, address: &str, chunk_id: usize, metrics: &Metrics) {
+        let mut state = self.state.lock().unwrap();
+        // Streamed alerts are a prefix of the final list; only the rest are new to the live view
+        let streamed = state.in_flight.remove(&(address.to_string(), chunk_id)).map_or(0, |in_flight| in_flight.alerts_streamed);
+        state.push_recent(metrics.alerts_generated.iter().skip(streamed));
+        let worker = state.workers.entry(address.to_string()).or_default();
+        worker.units_completed += 1;
+        worker.logs_processed += metrics.total_logs_processed;
+        worker.alerts_found += metrics.alerts_generated.len();
+        worker.busy_time_ms += metrics.execution_time_ms;
+        worker.logs_per_second = if worker.busy_time_ms > 0.0 {
+            (worker.logs_processed as f64 / worker.busy_time_ms) * 1000.0
+        } else {
+            0.0
+        };
+    }
+
+    pub(super) fn chunk_failed(&self, address: &str, chunk_id: usize) {
+        self.state.lock().unwrap().in_flight.remove(&(address.to_string(), chunk_id));
+    }
+
+    pub(super) fn finish(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.finished_after_ms = Some(state.start.elapsed().as_secs_f64() * 1000.0);
+        state.error = error;
+        state.in_flight.clear();
+    }
+
+    /// Completed work per worker, in address order.
+    pub fn worker_breakdown(&self) -> Vec<WorkerMetrics> {
+        self.state.lock().unwrap().workers.values().cloned().collect()
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let state = self.state.lock().unwrap();
+        let mut workers = state.workers.clone();
+        for ((address, _), in_flight) in &state.in_flight {
+            let worker = workers.entry(address.clone()).or_default();
+            worker.logs_processed += in_flight.logs_processed;
+            worker.alerts_found += in_flight.alerts_so_far;
+        }
+        ProgressSnapshot {
+            running: state.finished_after_ms.is_none(),
+            error: state.error.clone(),
+            started_at: state.started_at,
+            elapsed_ms: state.finished_after_ms.unwrap_or_else(|| state.start.elapsed().as_secs_f64() * 1000.0),
+            total_units: state.total_units,
+            completed_units: workers.values().map(|worker| worker.units_completed).sum(),
+            total_logs: state.total_logs,
+            logs_processed: workers.values().map(|worker| worker.logs_processed).sum(),
+            alerts_so_far: workers.values().map(|worker| worker.alerts_found).sum(),
+            recent_alerts: state.recent_alerts.iter().cloned().collect(),
+            workers: workers.into_values().collect(),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::models::{MasterMessage, RegistryMessage, RegistryReply, WorkerMessage};
+
+pub type ProtocolResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Bumped whenever `WorkerMessage`/`MasterMessage`/`RegistryMessage`/`RegistryReply` or the framing change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Largest payload either side will send or accept, checked against the length prefix
+/// before any buffer is allocated, and against the inflated size of compressed frames.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Largest frame accepted before the peer has completed the handshake (and authenticated, when a
+/// shared secret is configured), so unauthenticated peers can't make us allocate `MAX_FRAME_SIZE`.
+pub const MAX_HANDSHAKE_FRAME_SIZE: u32 = 16 * 1024;
+
+// Flag bits in the byte that follows the length prefix
+const FLAG_COMPRESSED: u8 = 0x01;
+const FLAG_MESSAGE_PACK: u8 = 0x02;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Encoding::Json),
+            "msgpack" | "messagepack" => Ok(Encoding::MessagePack),
+            _ => Err(format!("Unknown wire encoding '{}', expected 'json' or 'msgpack'", s)),
+        }
+    }
+}
+
+/// How a peer encodes the frames it sends. Agreed during the handshake; handshake frames
+/// themselves always use `FrameCodec::HANDSHAKE`. Each frame records its own encoding and
+/// compression in its flag byte, so the reader never needs to know the codec.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    pub encoding: Encoding,
+    /// Deflate bulk frames (see `Frame::is_bulk`); small control frames are never compressed.
+    pub compression: bool,
+}
+
+impl FrameCodec {
+    pub const HANDSHAKE: FrameCodec = FrameCodec { encoding: Encoding::Json, compression: false };
+}
+
+/// Messages that can be framed. Bulk messages carry log data and are worth compressing.
+pub trait Frame {
+    fn is_bulk(&self) -> bool;
+}
+
+impl Frame for WorkerMessage {
+    fn is_bulk(&self) -> bool {
+        matches!(self, WorkerMessage::LogChunk { .. } | WorkerMessage::RawLogChunk { .. })
+    }
+}
+
+impl Frame for MasterMessage {
+    fn is_bulk(&self) -> bool {
+        match self {
+            MasterMessage::AnalysisResult(_) => true,
+            MasterMessage::Progress(progress) => !progress.new_alerts.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl Frame for RegistryMessage {
+    fn is_bulk(&self) -> bool {
+        false
+    }
+}
+
+impl Frame for RegistryReply {
+    fn is_bulk(&self) -> bool {
+        false
+    }
+}
+
+/// Encodes one frame body: the flag byte followed by the (possibly deflated) payload.
+pub fn encode_frame<T: Serialize + Frame>(message: &T, codec: FrameCodec) -> ProtocolResult<Vec<u8>> {
+    let (mut flags, payload) = match codec.encoding {
+        Encoding::Json => (0, serde_json::to_vec(message)?),
+        Encoding::MessagePack => (FLAG_MESSAGE_PACK, rmp_serde::to_vec_named(message)?),
+    };
+
+    let mut body = Vec::with_capacity(payload.len() + 1);
+    if codec.compression && message.is_bulk() {
+        flags |= FLAG_COMPRESSED;
+        body.push(flags);
+        let mut encoder = DeflateEncoder::new(body, Compression::fast());
+        encoder.write_all(&payload)?;
+        body = encoder.finish()?;
+    } else {
+        body.push(flags);
+        body.extend_from_slice(&payload);
+    }
+
+    if body.len() > MAX_FRAME_SIZE as usize {
+        return Err(format!("Frame of {} bytes exceeds the {} byte limit", body.len(), MAX_FRAME_SIZE).into());
+    }
+    Ok(body)
+}
+
+pub fn decode_frame<T: DeserializeOwned>(body: &[u8]) -> ProtocolResult<T> {
+    decode_frame_within(body, MAX_FRAME_SIZE)
+}
+
+fn decode_frame_within<T: DeserializeOwned>(body: &[u8], max_size: u32) -> ProtocolResult<T> {
+    let (&flags, payload) = body.split_first().ok_or("Empty frame")?;
+
+    let inflated;
+    let payload = if flags & FLAG_COMPRESSED != 0 {
+        let mut buffer = Vec::new();
+        // Read one byte past the limit to detect oversized frames without inflating all of them
+        DeflateDecoder::new(payload).take(max_size as u64 + 1).read_to_end(&mut buffer)?;
+        if buffer.len() > max_size as usize {
+            return Err(format!("Compressed frame inflates beyond the {} byte limit", max_size).into());
+        }
+        inflated = buffer;
+        &inflated[..]
+    } else {
+        payload
+    };
+
+    if flags & FLAG_MESSAGE_PACK != 0 {
+        Ok(rmp_serde::from_slice(payload)?)
+    } else {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// Writes one frame: a little-endian u32 length prefix followed by the encoded frame body.
+pub async fn write_message<W, T>(stream: &mut W, message: &T, codec: FrameCodec) -> ProtocolResult<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize + Frame,
+{
+    let body = encode_frame(message, codec)?;
+    stream.write_all(&(body.len() as u32).to_le_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+pub async fn read_message<R, T>(stream: &mut R) -> ProtocolResult<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    read_message_within(stream, MAX_FRAME_SIZE).await
+}
+
+/// Reads one frame of at most `max_size` bytes, e.g. `MAX_HANDSHAKE_FRAME_SIZE` from a peer that
+/// hasn't authenticated yet.
+pub async fn read_message_within<R, T>(stream: &mut R, max_size: u32) -> ProtocolResult<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes).await?;
+    let len = u32::from_le_bytes(len_bytes);
+    if len > max_size {
+        return Err(format!("Incoming frame of {} bytes exceeds the {} byte limit", len, max_size).into());
+    }
+    let mut buffer = vec![0u8; len as usize];
+    stream.read_exact(&mut buffer).await?;
+    decode_frame_within(&buffer, max_size)
+}
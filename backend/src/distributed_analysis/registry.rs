@@ -0,0 +1,352 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::models::{RegistryMessage, RegistryReply, WorkerCapabilities};
+use super::master::{MasterConfig, WorkerSession};
+use super::protocol::{read_message_within, write_message, FrameCodec, ProtocolResult, MAX_HANDSHAKE_FRAME_SIZE, PROTOCOL_VERSION};
+use super::security::{new_nonce, ClusterSecurity};
+
+/// Registrations served at once; further connections are closed unanswered until one finishes.
+const MAX_PENDING_REGISTRATIONS: usize = 32;
+
+/// Verifications, i.e. connections back to an advertised address, allowed per peer IP within
+/// `VERIFICATION_WINDOW`. Heartbeats from live workers don't need one.
+const MAX_VERIFICATIONS_PER_PEER: usize = 10;
+const VERIFICATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// The only reason a registration is refused with, whatever went wrong; the details are logged.
+const REGISTRATION_REJECTED: &str = "Registration rejected";
+
+fn default_registry_address() -> String {
+    "127.0.0.1:8070".to_string()
+}
+
+fn default_registration_ttl_seconds() -> u64 {
+    30
+}
+
+/// Cluster topology, loaded from the cluster file. Workers listed here are always considered;
+/// others join by registering at `registry_address`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    #[serde(default = "default_registry_address")]
+    pub registry_address: String,
+    /// A worker that hasn't been seen for this long is no longer live.
+    #[serde(default = "default_registration_ttl_seconds")]
+    pub registration_ttl_seconds: u64,
+    /// Static worker endpoints, e.g. `"10.0.0.5:8081"`.
+    #[serde(default)]
+    pub workers: Vec<String>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            registry_address: default_registry_address(),
+            registration_ttl_seconds: default_registration_ttl_seconds(),
+            workers: Vec::new(),
+        }
+    }
+}
+
+pub fn load_cluster_config<P: AsRef<Path>>(path: P) -> Result<ClusterConfig, String> {
+    let file_content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read cluster file: {}", e))?;
+
+    serde_json::from_str(&file_content)
+        .map_err(|e| format!("Failed to deserialize cluster file: {}", e))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerSource {
+    Static,
+    Registered,
+}
+
+/// One worker as reported by the cluster status API.
+#[derive(Debug, Serialize, Clone)]
+pub struct WorkerStatus {
+    pub address: String,
+    pub source: WorkerSource,
+    /// From the worker's last successful handshake.
+    pub capabilities: Option<WorkerCapabilities>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub live: bool,
+}
+
+/// Workers known to the master: the static ones from the cluster file, plus any that registered.
+/// A worker is live while it has been seen within the registration TTL.
+pub struct WorkerRegistry {
+    workers: Mutex<BTreeMap<String, WorkerStatus>>,
+    ttl: chrono::Duration,
+    // Recent verification times per registering peer, oldest first
+    verifications: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl WorkerRegistry {
+    pub fn new(config: &ClusterConfig) -> Self {
+        let workers = config.workers
+            .iter()
+            .map(|address| (address.clone(), WorkerStatus {
+                address: address.clone(),
+                source: WorkerSource::Static,
+                capabilities: None,
+                last_seen: None,
+                last_error: None,
+                live: false,
+            }))
+            .collect();
+        WorkerRegistry {
+            workers: Mutex::new(workers),
+            ttl: chrono::Duration::seconds(config.registration_ttl_seconds as i64),
+            verifications: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Heartbeats and probes run three times per TTL, so one lost round doesn't drop a worker.
+    fn heartbeat_interval(&self) -> Duration {
+        (self.ttl / 3).to_std().unwrap_or_default().max(Duration::from_secs(1))
+    }
+
+    fn is_live(&self, worker: &WorkerStatus) -> bool {
+        worker.last_seen.is_some_and(|seen| Utc::now() - seen < self.ttl)
+    }
+
+    /// Every known worker, with `live` evaluated now.
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        self.workers.lock().unwrap()
+            .values()
+            .map(|worker| WorkerStatus { live: self.is_live(worker), ..worker.clone() })
+            .collect()
+    }
+
+    /// Addresses of the live workers, for `run_master`.
+    pub fn live_addresses(&self) -> Vec<String> {
+        self.workers.lock().unwrap()
+            .values()
+            .filter(|worker| self.is_live(worker))
+            .map(|worker| worker.address.clone())
+            .collect()
+    }
+
+    fn record_seen(&self, address: &str, source: WorkerSource, capabilities: Option<WorkerCapabilities>) {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers.entry(address.to_string()).or_insert_with(|| WorkerStatus {
+            address: address.to_string(),
+            source,
+            capabilities: None,
+            last_seen: None,
+            last_error: None,
+            live: false,
+        });
+        if capabilities.is_some() {
+            worker.capabilities = capabilities;
+        }
+        worker.last_seen = Some(Utc::now());
+        worker.last_error = None;
+    }
+
+    fn record_error(&self, address: &str, error: String) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(address) {
+            worker.last_error = Some(error);
+        }
+    }
+
+    // Whether a registration for `address` can refresh the worker without reconnecting to it.
+    fn needs_verification(&self, address: &str) -> bool {
+        self.workers.lock().unwrap().get(address).is_none_or(|worker| !self.is_live(worker))
+    }
+
+    // Counts a verification for `peer` unless it already used up its allowance for the window.
+    fn allow_verification(&self, peer: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut verifications = self.verifications.lock().unwrap();
+        verifications.retain(|_, times| {
+            while times.front().is_some_and(|time| now.duration_since(*time) >= VERIFICATION_WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = verifications.entry(peer).or_default();
+        if times.len() >= MAX_VERIFICATIONS_PER_PEER {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Accepts worker registrations on `registry_address` and keeps the static workers' liveness
+    /// current by probing them, until the process exits. Registrations need the shared secret, so
+    /// without one only the static workers are used.
+    pub async fn start(self: Arc<Self>, registry_address: String, config: MasterConfig) -> ProtocolResult<()> {
+        let registry = Arc::clone(&self);
+        let probe_config = config.clone();
+        tokio::spawn(registry.probe_static_workers(probe_config));
+
+        if !config.security.has_shared_secret() {
+            println!("Worker registration disabled: it requires CLUSTER_SHARED_SECRET");
+            return Ok(());
+        }
+        let listener = TcpListener::bind(&registry_address).await
+            .map_err(|e| format!("Failed to bind worker registry on {}: {}", registry_address, e))?;
+        println!("Worker registry listening on {}", registry_address);
+
+        let pending = Arc::new(Semaphore::new(MAX_PENDING_REGISTRATIONS));
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Worker registry failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(permit) = Arc::clone(&pending).try_acquire_owned() else {
+                    continue;
+                };
+                let registry = Arc::clone(&self);
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = registry.serve_registration(socket, peer.ip(), &config).await {
+                        eprintln!("Registration from {} failed: {}", peer, e);
+                    }
+                    drop(permit);
+                });
+            }
+        });
+        Ok(())
+    }
+
+    // The registrant proves the shared secret before the master connects anywhere for it, and a new
+    // worker is only trusted once the master has completed a full (TLS / shared-secret) handshake
+    // with the address it advertised. Refusals don't say why, so the registry can't be used to
+    // probe other hosts.
+    async fn serve_registration(&self, mut socket: TcpStream, peer: IpAddr, config: &MasterConfig) -> ProtocolResult<()> {
+        let nonce = new_nonce();
+        write_message(&mut socket, &RegistryReply::Challenge { nonce: nonce.clone() }, FrameCodec::HANDSHAKE).await?;
+        let RegistryMessage::Register { protocol_version, address, proof } = timeout(config.heartbeat_timeout, read_message_within(&mut socket, MAX_HANDSHAKE_FRAME_SIZE)).await
+            .map_err(|_| "No registration received in time")??;
+        if protocol_version != PROTOCOL_VERSION {
+            let error = format!("Unsupported protocol version {}, expected {}", protocol_version, PROTOCOL_VERSION);
+            write_message(&mut socket, &RegistryReply::Rejected(error.clone()), FrameCodec::HANDSHAKE).await?;
+            return Err(error.into());
+        }
+
+        let admitted = self.admit(&nonce, &address, &proof, peer, config).await;
+        let reply = match admitted {
+            Ok(()) => RegistryReply::Registered { heartbeat_interval_seconds: self.heartbeat_interval().as_secs() },
+            Err(_) => RegistryReply::Rejected(REGISTRATION_REJECTED.to_string()),
+        };
+        write_message(&mut socket, &reply, FrameCodec::HANDSHAKE).await?;
+        admitted.map_err(Into::into)
+    }
+
+    // Lists `address` if the registrant proved the shared secret and the worker is either live
+    // already or completes a handshake now.
+    async fn admit(&self, nonce: &str, address: &str, proof: &str, peer: IpAddr, config: &MasterConfig) -> Result<(), String> {
+        if !config.security.verify_registration_proof(nonce, address, proof) {
+            return Err(format!("Failed shared-secret authentication for {}", address));
+        }
+        if !self.needs_verification(address) {
+            self.record_seen(address, WorkerSource::Registered, None);
+            return Ok(());
+        }
+        if !self.allow_verification(peer) {
+            return Err(format!("Too many verifications requested; not connecting to {}", address));
+        }
+        let capabilities = probe(address, config).await
+            .map_err(|e| format!("Could not verify worker at {}: {}", address, e))?;
+        println!("Registered worker {} at {} ({} cores, capacity {})", capabilities.worker_id, address, capabilities.cores, capabilities.capacity);
+        self.record_seen(address, WorkerSource::Registered, Some(capabilities));
+        Ok(())
+    }
+
+    // Probes run concurrently, so one stalled worker doesn't hold back the others' refreshes.
+    async fn probe_static_workers(self: Arc<Self>, config: MasterConfig) {
+        let mut interval = tokio::time::interval(self.heartbeat_interval());
+        loop {
+            interval.tick().await;
+            let static_addresses: Vec<String> = self.workers.lock().unwrap()
+                .values()
+                .filter(|worker| worker.source == WorkerSource::Static)
+                .map(|worker| worker.address.clone())
+                .collect();
+            let mut probes = JoinSet::new();
+            for address in static_addresses {
+                let (registry, config) = (Arc::clone(&self), config.clone());
+                probes.spawn(async move {
+                    match probe(&address, &config).await {
+                        Ok(capabilities) => registry.record_seen(&address, WorkerSource::Static, Some(capabilities)),
+                        Err(e) => registry.record_error(&address, e.to_string()),
+                    }
+                });
+            }
+            while probes.join_next().await.is_some() {}
+        }
+    }
+}
+
+// Opens and closes one session within `heartbeat_timeout`, returning what the worker reported in
+// the handshake.
+async fn probe(address: &str, config: &MasterConfig) -> ProtocolResult<WorkerCapabilities> {
+    let session = async {
+        let session = WorkerSession::connect(address, config).await?;
+        let capabilities = session.capabilities.clone();
+        session.finish().await?;
+        Ok(capabilities)
+    };
+    timeout(config.heartbeat_timeout, session).await
+        .map_err(|_| format!("Worker {} did not answer the probe within {:?}", address, config.heartbeat_timeout))?
+}
+
+/// Worker side: announces `address` to the registry until the process exits, as often as the registry
+/// asks. Retries every `retry_interval` while the registry is unreachable or rejects the worker.
+pub async fn register_periodically(registry_address: String, address: String, retry_interval: Duration, security: Arc<ClusterSecurity>) {
+    let mut registered = false;
+    loop {
+        let attempt = timeout(retry_interval, register(&registry_address, &address, &security)).await
+            .unwrap_or_else(|_| Err("No reply from the registry in time".into()));
+        let wait = match attempt {
+            Ok(heartbeat_interval) => {
+                if !registered {
+                    println!("Registered with master at {} as {}", registry_address, address);
+                    registered = true;
+                }
+                heartbeat_interval
+            },
+            Err(e) => {
+                eprintln!("Registration with master at {} failed: {}", registry_address, e);
+                registered = false;
+                retry_interval
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+async fn register(registry_address: &str, address: &str, security: &ClusterSecurity) -> ProtocolResult<Duration> {
+    let mut stream = TcpStream::connect(registry_address).await?;
+    let nonce = match read_message_within::<_, RegistryReply>(&mut stream, MAX_HANDSHAKE_FRAME_SIZE).await? {
+        RegistryReply::Challenge { nonce } => nonce,
+        other => return Err(format!("Expected a challenge from the registry, got {:?}", other).into()),
+    };
+    let proof = security.registration_proof(&nonce, address).ok_or("Registering requires CLUSTER_SHARED_SECRET")?;
+    let message = RegistryMessage::Register { protocol_version: PROTOCOL_VERSION, address: address.to_string(), proof };
+    write_message(&mut stream, &message, FrameCodec::HANDSHAKE).await?;
+    match read_message_within::<_, RegistryReply>(&mut stream, MAX_HANDSHAKE_FRAME_SIZE).await? {
+        RegistryReply::Registered { heartbeat_interval_seconds } => Ok(Duration::from_secs(heartbeat_interval_seconds.max(1))),
+        RegistryReply::Rejected(e) => Err(e.into()),
+        RegistryReply::Challenge { .. } => Err("Unexpected second challenge from the registry".into()),
+    }
+}
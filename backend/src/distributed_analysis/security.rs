@@ -0,0 +1,193 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::protocol::ProtocolResult;
+
+/// A master/worker connection, plain TCP or TLS.
+pub trait ClusterStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClusterStream for T {}
+
+/// PEM files for the cluster links. The same set of options configures both roles: the
+/// certificate and key identify this node, and `ca_file` is the authority the peer must chain to.
+#[derive(Debug, Clone, Default)]
+pub struct TlsFiles {
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub ca_file: Option<String>,
+}
+
+/// Transport security for master/worker links.
+///
+/// - Workers with a certificate and key accept TLS only; with `ca_file` they also demand a
+///   client certificate signed by it (mutual TLS).
+/// - Masters with `ca_file` connect over TLS and verify the worker against it, presenting their
+///   own certificate when one is configured.
+/// - With a shared secret, both sides prove knowledge of it during the handshake
+///   (see `session_proof`), over TLS or not.
+#[derive(Clone, Default)]
+pub struct ClusterSecurity {
+    acceptor: Option<TlsAcceptor>,
+    connector: Option<TlsConnector>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for ClusterSecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClusterSecurity")
+            .field("tls", &(self.acceptor.is_some() || self.connector.is_some()))
+            .field("shared_secret", &self.shared_secret.is_some())
+            .finish()
+    }
+}
+
+fn load_certs(path: &str) -> ProtocolResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| format!("Failed to read certificates from {}: {}", path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> ProtocolResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| format!("Failed to read private key from {}: {}", path, e).into())
+}
+
+fn load_roots(path: &str) -> ProtocolResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+impl ClusterSecurity {
+    /// Builds the worker (TLS server) side. TLS is enabled when both certificate and key are given.
+    pub fn for_worker(tls: &TlsFiles, shared_secret: Option<String>) -> ProtocolResult<Self> {
+        let acceptor = match (&tls.cert_file, &tls.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let builder = match &tls.ca_file {
+                    Some(ca_file) => {
+                        let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca_file)?)).build()?;
+                        ServerConfig::builder().with_client_cert_verifier(verifier)
+                    },
+                    None => ServerConfig::builder().with_no_client_auth(),
+                };
+                let config = builder.with_single_cert(load_certs(cert_file)?, load_key(key_file)?)?;
+                Some(TlsAcceptor::from(Arc::new(config)))
+            },
+            (None, None) => None,
+            _ => return Err("Both --tls-cert and --tls-key are required to enable TLS".into()),
+        };
+        Ok(ClusterSecurity { acceptor, connector: None, shared_secret: shared_secret.map(String::into_bytes) })
+    }
+
+    /// Builds the master (TLS client) side. TLS is enabled when a CA file is given.
+    pub fn for_master(tls: &TlsFiles, shared_secret: Option<String>) -> ProtocolResult<Self> {
+        let connector = match &tls.ca_file {
+            Some(ca_file) => {
+                let builder = ClientConfig::builder().with_root_certificates(load_roots(ca_file)?);
+                let config = match (&tls.cert_file, &tls.key_file) {
+                    (Some(cert_file), Some(key_file)) => builder.with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)?,
+                    (None, None) => builder.with_no_client_auth(),
+                    _ => return Err("Both --tls-cert and --tls-key are required for a client certificate".into()),
+                };
+                Some(TlsConnector::from(Arc::new(config)))
+            },
+            None => None,
+        };
+        Ok(ClusterSecurity { acceptor: None, connector, shared_secret: shared_secret.map(String::into_bytes) })
+    }
+
+    pub fn has_shared_secret(&self) -> bool {
+        self.shared_secret.is_some()
+    }
+
+    pub async fn connect(&self, address: &str) -> ProtocolResult<Box<dyn ClusterStream>> {
+        let stream = TcpStream::connect(address).await
+            .map_err(|e| format!("Failed to connect to worker at {}: {}", address, e))?;
+        match &self.connector {
+            Some(connector) => {
+                let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+                let server_name = ServerName::try_from(host.to_string())
+                    .map_err(|e| format!("Invalid TLS server name {}: {}", host, e))?;
+                let tls_stream = connector.connect(server_name, stream).await
+                    .map_err(|e| format!("TLS handshake with worker {} failed: {}", address, e))?;
+                Ok(Box::new(tls_stream))
+            },
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> ProtocolResult<Box<dyn ClusterStream>> {
+        match &self.acceptor {
+            Some(acceptor) => Ok(Box::new(acceptor.accept(stream).await?)),
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    // HMAC-SHA256 of `parts` under the shared secret.
+    fn mac(&self, parts: &[&str]) -> Option<Hmac<Sha256>> {
+        let secret = self.shared_secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        parts.iter().for_each(|part| mac.update(part.as_bytes()));
+        Some(mac)
+    }
+
+    // Constant-time check of a peer's proof over `parts`.
+    fn verify_mac(&self, parts: &[&str], proof: &str) -> bool {
+        let (Some(mac), Some(proof_bytes)) = (self.mac(parts), from_hex(proof)) else { return false };
+        mac.verify_slice(&proof_bytes).is_ok()
+    }
+
+    /// HMAC-SHA256 over both handshake nonces, bound to the side producing it so a proof
+    /// can't be reflected back. `None` when no shared secret is configured.
+    pub fn session_proof(&self, role: &str, master_nonce: &str, worker_nonce: &str) -> Option<String> {
+        self.mac(&[role, master_nonce, worker_nonce]).map(|mac| to_hex(&mac.finalize().into_bytes()))
+    }
+
+    /// Constant-time check of a peer's proof.
+    pub fn verify_proof(&self, role: &str, master_nonce: &str, worker_nonce: &str, proof: &str) -> bool {
+        self.verify_mac(&[role, master_nonce, worker_nonce], proof)
+    }
+
+    /// A worker's answer to the registry's challenge: HMAC-SHA256 over the registry's nonce and the
+    /// address it asks the master to connect to. `None` when no shared secret is configured.
+    pub fn registration_proof(&self, registry_nonce: &str, address: &str) -> Option<String> {
+        self.mac(&["registration", registry_nonce, address]).map(|mac| to_hex(&mac.finalize().into_bytes()))
+    }
+
+    pub fn verify_registration_proof(&self, registry_nonce: &str, address: &str, proof: &str) -> bool {
+        self.verify_mac(&["registration", registry_nonce, address], proof)
+    }
+}
+
+/// Fresh random nonce for one handshake, hex-encoded.
+pub fn new_nonce() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -1,82 +1,328 @@
+use chrono::{DateTime, Utc};
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
 
-use crate::models::{Metrics, Alert, WorkerMessage, MasterMessage};
+use crate::models::{Alert, ChunkProgress, LogEntry, Metrics, WorkerCapabilities, WorkerMessage, MasterMessage};
 
+use crate::log_processor::{parse_log_line, with_timing};
+use crate::parser_config::ParserSet;
+use crate::rules_engine::RulesEngine;
+use crate::sequential_analysis::analyze_with_detector;
 use crate::threat_detection::ThreatDetector;
+use super::protocol::{read_message_within, write_message, Encoding, FrameCodec, ProtocolResult, MAX_FRAME_SIZE, MAX_HANDSHAKE_FRAME_SIZE, PROTOCOL_VERSION};
+use super::security::{new_nonce, ClusterSecurity, ClusterStream};
+use super::registry::register_periodically;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use std::error::Error;
+/// How often a worker reports progress while analyzing a chunk. Progress frames are also its
+/// heartbeat, so this must stay well below the master's heartbeat timeout.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
-pub async fn run_worker(worker_id: usize) -> Result<(), Box<dyn Error>> {
-    let addr = format!("127.0.0.1:{}", 8081 + worker_id as u16);
-    let listener: TcpListener = TcpListener::bind(&addr).await?;
-    println!("Worker {} listening on {}", worker_id, addr);
+/// Entries analyzed between updates of the progress counters.
+const PROGRESS_BATCH_SIZE: usize = 1_000;
 
-    let mut threat_detector: Option<ThreatDetector> = None;
-    let mut processed_logs_count = 0;
-    let mut generated_alerts: Vec<Alert> = Vec::new();
+/// Encodings this worker can speak, best first.
+const SUPPORTED_ENCODINGS: [Encoding; 2] = [Encoding::MessagePack, Encoding::Json];
+
+/// How long a connecting master gets for the TLS handshake and the Hello (and Authenticate) exchange.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a worker retries registering while the master's registry is unreachable.
+const REGISTRATION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where a worker listens and how it announces itself.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    pub worker_id: usize,
+    pub listen_address: String,
+    /// Address the master should connect to, if it differs from `listen_address` (e.g. when listening on 0.0.0.0).
+    pub advertise_address: Option<String>,
+    /// Concurrent sessions the master may open; defaults to the core count.
+    pub capacity: Option<usize>,
+    /// Master registry to register with; without one, the worker must be listed in the master's cluster file.
+    pub registry_address: Option<String>,
+}
+
+/// Listens on this worker's address and serves each master connection as one session.
+pub async fn run_worker(config: WorkerConfig, security: Arc<ClusterSecurity>) -> ProtocolResult<()> {
+    let worker_id = config.worker_id;
+    let listener: TcpListener = TcpListener::bind(&config.listen_address).await
+        .map_err(|e| format!("Failed to bind worker on {}: {}", config.listen_address, e))?;
+    println!("Worker {} listening on {} ({:?})", worker_id, config.listen_address, security);
+
+    if let Some(registry_address) = config.registry_address.clone() {
+        let advertise_address = config.advertise_address.clone().unwrap_or_else(|| config.listen_address.clone());
+        tokio::spawn(register_periodically(registry_address, advertise_address, REGISTRATION_RETRY_INTERVAL, Arc::clone(&security)));
+    }
+    let capabilities = Arc::new(capabilities(&config));
 
     loop {
-        let (mut socket, _): (tokio::net::TcpStream, std::net::SocketAddr) = listener.accept().await?;
-        println!("Worker {} accepted connection from master", worker_id);
-
-        let mut len_bytes = [0u8; 4];
-        socket.read_exact(&mut len_bytes).await?;
-        let len = u32::from_le_bytes(len_bytes) as usize;
-        let mut buffer = vec![0u8; len];
-        socket.read_exact(&mut buffer).await?;
-
-        let message: WorkerMessage = serde_json::from_slice(&buffer)?;
-
-        match message {
-            WorkerMessage::LogChunk(log_entries) => {
-                println!("Worker {} received log chunk of {} entries", worker_id, log_entries.len());
-                processed_logs_count += log_entries.len();
-                if let Some(detector) = &mut threat_detector {
-                    for log_entry in log_entries {
-                        if let Some(alert) = detector.detect_threats(&log_entry) {
-                            generated_alerts.push(alert);
-                        }
+        let (tcp, peer) = listener.accept().await?;
+        println!("Worker {} accepted connection from master at {}", worker_id, peer);
+        let security = Arc::clone(&security);
+        let capabilities = Arc::clone(&capabilities);
+        tokio::spawn(async move {
+            let handshake = async {
+                let socket = security.accept(tcp).await?;
+                let mut connection = Connection { socket, codec: FrameCodec::HANDSHAKE, frame_limit: MAX_HANDSHAKE_FRAME_SIZE };
+                handshake(&capabilities, &mut connection, &security).await?;
+                ProtocolResult::Ok(connection)
+            };
+            let mut connection = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => {
+                    eprintln!("Worker {} rejected connection from {}: {}", worker_id, peer, e);
+                    return;
+                },
+                Err(_) => {
+                    eprintln!("Worker {} dropped connection from {}: no handshake within {:?}", worker_id, peer, HANDSHAKE_TIMEOUT);
+                    return;
+                },
+            };
+            if let Err(e) = serve_session(worker_id, &mut connection).await {
+                eprintln!("Worker {} session with {} failed: {}", worker_id, peer, e);
+            }
+        });
+    }
+}
+
+fn capabilities(config: &WorkerConfig) -> WorkerCapabilities {
+    let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    WorkerCapabilities {
+        worker_id: config.worker_id,
+        cores,
+        capacity: config.capacity.unwrap_or(cores).max(1),
+        max_frame_size: MAX_FRAME_SIZE,
+        encodings: SUPPORTED_ENCODINGS.to_vec(),
+    }
+}
+
+// Picks the master's most preferred encoding that this worker supports.
+fn negotiate(encodings: &[Encoding], compression: bool) -> Option<FrameCodec> {
+    encodings
+        .iter()
+        .find(|encoding| SUPPORTED_ENCODINGS.contains(encoding))
+        .map(|&encoding| FrameCodec { encoding, compression })
+}
+
+// The master connection plus the codec agreed for this session.
+struct Connection {
+    socket: Box<dyn ClusterStream>,
+    codec: FrameCodec,
+    // `MAX_HANDSHAKE_FRAME_SIZE` until the master has completed the handshake
+    frame_limit: u32,
+}
+
+impl Connection {
+    async fn send(&mut self, message: &MasterMessage) -> ProtocolResult<()> {
+        write_message(&mut self.socket, message, self.codec).await
+    }
+
+    // Reports a failure to the master before the session is torn down.
+    async fn fail(&mut self, message: String) -> ProtocolResult<()> {
+        self.send(&MasterMessage::Error(message.clone())).await?;
+        Err(message.into())
+    }
+
+    // Reads one frame, replying with an error frame if it cannot be decoded.
+    async fn next_message(&mut self) -> ProtocolResult<WorkerMessage> {
+        match read_message_within(&mut self.socket, self.frame_limit).await {
+            Ok(message) => Ok(message),
+            Err(e) => {
+                // Best effort: the connection may already be gone
+                let _ = self.send(&MasterMessage::Error(format!("Invalid frame: {}", e))).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+enum ChunkData {
+    Parsed(Vec<LogEntry>),
+    Raw(Vec<String>, Arc<ParserSet>),
+}
+
+// Counters shared between the analysis thread and the session task.
+#[derive(Default)]
+struct PartialResult {
+    logs_processed: usize,
+    alerts_so_far: usize,
+    unsent_alerts: Vec<Alert>,
+}
+
+// Runs parsing and detection off the async runtime in batches of `PROGRESS_BATCH_SIZE`, sending a
+// progress frame every `PROGRESS_INTERVAL` until it finishes. Each chunk is a complete key partition,
+// so it gets a fresh detector whose window state carries across the batches. Absence rules are
+// finally checked up to `watermark`, the newest time in the whole job, not just in this chunk.
+async fn analyze_chunk(
+    connection: &mut Connection,
+    chunk_id: usize,
+    data: ChunkData,
+    watermark: Option<DateTime<Utc>>,
+    rules_engine: Arc<RulesEngine>,
+) -> ProtocolResult<Metrics> {
+    let start_time = Instant::now();
+    let partial = Arc::new(Mutex::new(PartialResult::default()));
+    let shared = Arc::clone(&partial);
+
+    let mut analysis = tokio::task::spawn_blocking(move || {
+        let mut detector = ThreatDetector::new(rules_engine);
+        let mut alerts: Vec<Alert> = Vec::new();
+        let mut logs_processed = 0;
+        let (mut late_entries, mut untimestamped_entries) = (0, 0);
+        let mut analyze_batch = |entries: &[LogEntry]| {
+            let metrics = analyze_with_detector(entries, &mut detector);
+            logs_processed += metrics.total_logs_processed;
+            late_entries += metrics.late_entries;
+            untimestamped_entries += metrics.untimestamped_entries;
+            let mut partial = shared.lock().unwrap();
+            partial.logs_processed = logs_processed;
+            partial.alerts_so_far += metrics.alerts_generated.len();
+            partial.unsent_alerts.extend(metrics.alerts_generated.iter().cloned());
+            alerts.extend(metrics.alerts_generated);
+        };
+        match data {
+            ChunkData::Parsed(entries) => entries.chunks(PROGRESS_BATCH_SIZE).for_each(analyze_batch),
+            ChunkData::Raw(lines, parsers) => {
+                for batch in lines.chunks(PROGRESS_BATCH_SIZE) {
+                    let entries: Vec<LogEntry> = batch.iter().map(|line| parse_log_line(line, &parsers)).collect();
+                    analyze_batch(&entries);
+                }
+            },
+        }
+        if let Some(watermark) = watermark {
+            detector.check_absences(watermark);
+        }
+        let absence_alerts = detector.take_pending_alerts();
+        {
+            let mut partial = shared.lock().unwrap();
+            partial.alerts_so_far += absence_alerts.len();
+            partial.unsent_alerts.extend(absence_alerts.iter().cloned());
+        }
+        alerts.extend(absence_alerts);
+        Metrics {
+            total_logs_processed: logs_processed,
+            execution_time_ms: 0.0,
+            logs_per_second: 0.0,
+            alerts_by_rule: Metrics::count_alerts_by_rule(&alerts),
+            alerts_generated: alerts,
+            late_entries,
+            untimestamped_entries,
+            mode: "Distributed Worker".to_string(),
+            worker_breakdown: Vec::new(),
+        }
+    });
+
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    ticker.tick().await; // The first tick completes immediately
+    loop {
+        tokio::select! {
+            result = &mut analysis => return Ok(with_timing(result?, start_time)),
+            _ = ticker.tick() => {
+                let progress = {
+                    let mut partial = partial.lock().unwrap();
+                    ChunkProgress {
+                        chunk_id,
+                        logs_processed: partial.logs_processed,
+                        alerts_so_far: partial.alerts_so_far,
+                        elapsed_ms: start_time.elapsed().as_secs_f64() * 1000.0,
+                        new_alerts: std::mem::take(&mut partial.unsent_alerts),
                     }
+                };
+                connection.send(&MasterMessage::Progress(progress)).await?;
+            },
+        }
+    }
+}
+
+// Hello exchange, plus authentication when there is a shared secret. Lifts the frame limit once
+// the master is trusted.
+async fn handshake(capabilities: &WorkerCapabilities, connection: &mut Connection, security: &ClusterSecurity) -> ProtocolResult<()> {
+    match connection.next_message().await? {
+        WorkerMessage::Hello { protocol_version, encodings, compression, nonce: master_nonce } if protocol_version == PROTOCOL_VERSION => {
+            let Some(codec) = negotiate(&encodings, compression) else {
+                return connection.fail(format!("No common wire encoding in {:?}", encodings)).await;
+            };
+            let worker_nonce = new_nonce();
+            let hello = MasterMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: capabilities.clone(),
+                codec,
+                nonce: worker_nonce.clone(),
+                proof: security.session_proof("worker", &master_nonce, &worker_nonce),
+            };
+            connection.send(&hello).await?;
+            connection.codec = codec;
+
+            if security.has_shared_secret() {
+                match connection.next_message().await? {
+                    WorkerMessage::Authenticate { proof } if security.verify_proof("master", &master_nonce, &worker_nonce, &proof) => {
+                        connection.send(&MasterMessage::Ack).await?;
+                    },
+                    WorkerMessage::Authenticate { .. } => return connection.fail("Shared-secret authentication failed".to_string()).await,
+                    _ => return connection.fail("Expected Authenticate after Hello".to_string()).await,
                 }
-                let response = MasterMessage::Ack;
-                let serialized_response = serde_json::to_vec(&response)?;
-                socket.write_all(&(serialized_response.len() as u32).to_le_bytes()).await?;
-                socket.write_all(&serialized_response).await?;
+            }
+        },
+        WorkerMessage::Hello { protocol_version, .. } => {
+            return connection.fail(format!("Unsupported protocol version {}, expected {}", protocol_version, PROTOCOL_VERSION)).await;
+        },
+        _ => return connection.fail("Expected Hello as the first frame".to_string()).await,
+    }
+    connection.frame_limit = MAX_FRAME_SIZE;
+    Ok(())
+}
+
+// Request/response loop after the handshake, until `Shutdown`.
+async fn serve_session(worker_id: usize, connection: &mut Connection) -> ProtocolResult<()> {
+    let mut rules_engine: Option<Arc<RulesEngine>> = None;
+    let mut parsers: Option<Arc<ParserSet>> = None;
+
+    loop {
+        match connection.next_message().await? {
+            WorkerMessage::Hello { .. } | WorkerMessage::Authenticate { .. } => {
+                connection.send(&MasterMessage::Error("Session already established".to_string())).await?;
+            },
+            WorkerMessage::LogChunk { chunk_id, entries, watermark } => {
+                println!("Worker {} received log chunk {} of {} entries", worker_id, chunk_id, entries.len());
+                let Some(engine) = &rules_engine else {
+                    connection.send(&MasterMessage::Error("Log chunk received before rules".to_string())).await?;
+                    continue;
+                };
+                let metrics = analyze_chunk(connection, chunk_id, ChunkData::Parsed(entries), watermark, Arc::clone(engine)).await?;
+                connection.send(&MasterMessage::AnalysisResult(metrics)).await?;
+            },
+            WorkerMessage::RawLogChunk { chunk_id, lines, watermark } => {
+                println!("Worker {} received raw log chunk {} of {} lines", worker_id, chunk_id, lines.len());
+                let (Some(engine), Some(parsers)) = (&rules_engine, &parsers) else {
+                    connection.send(&MasterMessage::Error("Raw log chunk received before rules and parsing rules".to_string())).await?;
+                    continue;
+                };
+                let data = ChunkData::Raw(lines, Arc::clone(parsers));
+                let metrics = analyze_chunk(connection, chunk_id, data, watermark, Arc::clone(engine)).await?;
+                connection.send(&MasterMessage::AnalysisResult(metrics)).await?;
             },
             WorkerMessage::Rules(rules) => {
                 println!("Worker {} received {} rules", worker_id, rules.len());
-                threat_detector = Some(ThreatDetector::new(rules));
-                let response = MasterMessage::Ack;
-                let serialized_response = serde_json::to_vec(&response)?;
-                socket.write_all(&(serialized_response.len() as u32).to_le_bytes()).await?;
-                socket.write_all(&serialized_response).await?;
+                rules_engine = Some(Arc::new(RulesEngine::from_rules(rules)));
+                connection.send(&MasterMessage::Ack).await?;
+            },
+            WorkerMessage::ParsingRules(parsing_rules) => {
+                println!("Worker {} received {} parsing rules", worker_id, parsing_rules.len());
+                parsers = Some(Arc::new(ParserSet::new(parsing_rules)));
+                connection.send(&MasterMessage::Ack).await?;
             },
             WorkerMessage::StartAnalysis => {
                 println!("Worker {} received start analysis command", worker_id);
                 // This message is now redundant as analysis happens on LogChunk receipt
-                let response = MasterMessage::Ack;
-                let serialized_response = serde_json::to_vec(&response)?;
-                socket.write_all(&(serialized_response.len() as u32).to_le_bytes()).await?;
-                socket.write_all(&serialized_response).await?;
+                connection.send(&MasterMessage::Ack).await?;
             },
             WorkerMessage::Shutdown => {
-                println!("Worker {} received shutdown command. Sending results and exiting.", worker_id);
-                let metrics = Metrics {
-                    total_logs_processed: processed_logs_count,
-                    execution_time_ms: 0.0, // Worker doesn't track overall time
-                    logs_per_second: 0.0, // Worker doesn't track overall rate
-                    alerts_generated: generated_alerts,
-                    mode: "Distributed Worker".to_string(),
-                };
-                let response = MasterMessage::AnalysisResult(metrics);
-                let serialized_response = serde_json::to_vec(&response)?;
-                socket.write_all(&(serialized_response.len() as u32).to_le_bytes()).await?;
-                socket.write_all(&serialized_response).await?;
-                break;
+                println!("Worker {} received shutdown command. Closing session.", worker_id);
+                connection.send(&MasterMessage::Ack).await?;
+                return Ok(());
             },
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,174 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::models::DetectorStats;
+use crate::window::GroupKey;
+
+/// Limits on the per-group state each stateful rule keeps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StateLimits {
+    /// Most groups one rule tracks; the least recently updated group makes room for a new one.
+    pub max_keys: Option<usize>,
+    /// Groups not updated for this long are forgotten, even if the rule could still use their state.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl StateLimits {
+    /// The idle timeout for a rule whose state stops mattering after `horizon` without updates:
+    /// the configured one if shorter.
+    pub fn idle_timeout(&self, horizon: Option<Duration>) -> Option<Duration> {
+        match (self.idle_timeout, horizon) {
+            (Some(configured), Some(horizon)) => Some(configured.min(horizon)),
+            (configured, horizon) => configured.or(horizon),
+        }
+    }
+}
+
+/// One group's state as saved in a detector snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupSnapshot<V> {
+    pub key: GroupKey,
+    pub updated: DateTime<Utc>,
+    pub state: V,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    // Position in `by_use`
+    tick: u64,
+    // Time of the latest update
+    updated: DateTime<Utc>,
+}
+
+/// Per-group state of one rule, bounded by `StateLimits`. Groups are ordered by their latest
+/// update, so idle groups are dropped from the front and the key budget evicts the least
+/// recently used one.
+#[derive(Debug)]
+pub struct GroupStore<V> {
+    groups: HashMap<GroupKey, Slot<V>>,
+    by_use: BTreeMap<u64, GroupKey>,
+    next_tick: u64,
+    max_keys: Option<usize>,
+    idle_timeout: Option<Duration>,
+    // State dropped since the last `take_dropped`, for owners that index it elsewhere; only
+    // collected after `collecting_dropped`
+    collect_dropped: bool,
+    dropped: Vec<(GroupKey, V)>,
+    expired: u64,
+    evicted: u64,
+}
+
+impl<V> GroupStore<V> {
+    /// Keeps at most `max_keys` groups and forgets those idle for longer than `idle_timeout`.
+    pub fn new(max_keys: Option<usize>, idle_timeout: Option<Duration>) -> Self {
+        GroupStore {
+            groups: HashMap::new(),
+            by_use: BTreeMap::new(),
+            next_tick: 0,
+            max_keys: max_keys.map(|max_keys| max_keys.max(1)),
+            idle_timeout,
+            collect_dropped: false,
+            dropped: Vec::new(),
+            expired: 0,
+            evicted: 0,
+        }
+    }
+
+    pub fn collecting_dropped(mut self) -> Self {
+        self.collect_dropped = true;
+        self
+    }
+
+    /// The group's state, created with `default` if it isn't tracked, marked as updated at `time`.
+    pub fn entry(&mut self, key: GroupKey, time: DateTime<Utc>, default: impl FnOnce() -> V) -> &mut V {
+        self.expire_idle(time);
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(slot) = self.groups.get_mut(&key) {
+            self.by_use.remove(&slot.tick);
+            slot.tick = tick;
+            slot.updated = slot.updated.max(time);
+        } else {
+            if self.max_keys.is_some_and(|max_keys| self.groups.len() >= max_keys) {
+                self.evict_least_recent();
+            }
+            self.groups.insert(key.clone(), Slot { value: default(), tick, updated: time });
+        }
+        self.by_use.insert(tick, key.clone());
+        &mut self.groups.get_mut(&key).expect("group was just inserted").value
+    }
+
+    /// Replaces the group's state, marked as updated at `time`.
+    pub fn insert(&mut self, key: GroupKey, time: DateTime<Utc>, value: V) {
+        let mut value = Some(value);
+        let slot = self.entry(key, time, || value.take().expect("taken once"));
+        if let Some(value) = value {
+            *slot = value;
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &GroupKey) -> Option<&mut V> {
+        self.groups.get_mut(key).map(|slot| &mut slot.value)
+    }
+
+    pub fn remove(&mut self, key: &GroupKey) -> Option<V> {
+        let slot = self.groups.remove(key)?;
+        self.by_use.remove(&slot.tick);
+        Some(slot.value)
+    }
+
+    /// Forgets groups last updated before `now - idle_timeout`.
+    pub fn expire_idle(&mut self, now: DateTime<Utc>) {
+        let Some(idle_timeout) = self.idle_timeout else { return };
+        while let Some((_, key)) = self.by_use.first_key_value() {
+            if self.groups.get(key).is_some_and(|slot| slot.updated >= now - idle_timeout) {
+                break;
+            }
+            self.pop_least_recent();
+            self.expired += 1;
+        }
+    }
+
+    fn evict_least_recent(&mut self) {
+        self.pop_least_recent();
+        self.evicted += 1;
+    }
+
+    fn pop_least_recent(&mut self) {
+        if let Some((_, key)) = self.by_use.pop_first() {
+            if let Some(slot) = self.groups.remove(&key) {
+                if self.collect_dropped {
+                    self.dropped.push((key, slot.value));
+                }
+            }
+        }
+    }
+
+    /// Re-adds saved groups in their order, within this store's limits.
+    pub fn restore(&mut self, groups: Vec<GroupSnapshot<V>>) {
+        for group in groups {
+            self.insert(group.key, group.updated, group.state);
+        }
+    }
+
+    /// State expired or evicted since the last call.
+    pub fn take_dropped(&mut self) -> Vec<(GroupKey, V)> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    pub fn stats(&self) -> DetectorStats {
+        DetectorStats { tracked_keys: self.groups.len(), expired_keys: self.expired, evicted_keys: self.evicted, ..DetectorStats::default() }
+    }
+}
+
+impl<V: Clone> GroupStore<V> {
+    /// Every group's state, least recently updated first.
+    pub fn snapshot(&self) -> Vec<GroupSnapshot<V>> {
+        self.by_use
+            .values()
+            .filter_map(|key| self.groups.get(key).map(|slot| GroupSnapshot { key: key.clone(), updated: slot.updated, state: slot.value.clone() }))
+            .collect()
+    }
+}
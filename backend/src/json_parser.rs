@@ -0,0 +1,172 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::log_processor::{parse_timestamp, set_entry_field};
+use crate::models::LogEntry;
+
+// Epoch timestamps above this are taken to be in milliseconds (it is in the year 5138 as seconds)
+const EPOCH_MILLIS_THRESHOLD: f64 = 1e11;
+
+/// The line as a JSON object, if it is one.
+pub fn parse_json_object(line: &str) -> Option<Map<String, Value>> {
+    match serde_json::from_str(line.trim()) {
+        Ok(Value::Object(object)) => Some(object),
+        _ => None,
+    }
+}
+
+// `$.client.ip`, `$client.ip` and `client.ip` all name the same path.
+fn path_segments(path: &str) -> Vec<&str> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.strip_prefix('.').unwrap_or(path).split('.').filter(|segment| !segment.is_empty()).collect()
+}
+
+fn value_at<'v>(object: &'v Map<String, Value>, segments: &[&str]) -> Option<&'v Value> {
+    let (last, parents) = segments.split_last()?;
+    let mut current = object;
+    for segment in parents {
+        current = current.get(*segment)?.as_object()?;
+    }
+    current.get(*last)
+}
+
+fn remove_at(object: &mut Map<String, Value>, segments: &[&str]) {
+    let Some((last, parents)) = segments.split_last() else { return };
+    let mut current = object;
+    for segment in parents {
+        match current.get_mut(*segment).and_then(Value::as_object_mut) {
+            Some(child) => current = child,
+            None => return,
+        }
+    }
+    current.remove(*last);
+}
+
+// Scalars as text; arrays and objects as their JSON. `null` is no value.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        Value::Bool(_) | Value::Number(_) | Value::Array(_) | Value::Object(_) => Some(value.to_string()),
+    }
+}
+
+// Text in any of the known formats, or seconds (or milliseconds) since the epoch.
+fn json_timestamp(value: &Value, reference: NaiveDate) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(text) => parse_timestamp(text, reference),
+        Value::Number(number) => {
+            let epoch = number.as_f64()?;
+            let millis = if epoch.abs() >= EPOCH_MILLIS_THRESHOLD { epoch } else { epoch * 1000.0 };
+            DateTime::from_timestamp_millis(millis.round() as i64)
+        },
+        _ => None,
+    }
+}
+
+// Nested objects become dotted keys, e.g. `{"request": {"method": "GET"}}` -> `request.method`.
+fn flatten(prefix: String, value: Value, into: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten(key, child, into);
+            }
+        },
+        value => into.push((prefix, value)),
+    }
+}
+
+/// Fills the entry from a JSON object. `field_map` maps `LogEntry` fields to JSON paths; every
+/// other key is flattened into `extra`, except that keys named after a typed field the map left
+/// unset fill that field. Timestamps without a year are placed relative to `reference`.
+pub fn apply_json_fields(entry: &mut LogEntry, field_map: &HashMap<String, String>, mut object: Map<String, Value>, reference: NaiveDate) {
+    // Resolve every path before removing any, so overlapping paths all see the original object
+    let mapped: Vec<(&String, Vec<&str>, Option<Value>)> = field_map
+        .iter()
+        .map(|(field_name, path)| {
+            let segments = path_segments(path);
+            let value = value_at(&object, &segments).cloned();
+            (field_name, segments, value)
+        })
+        .collect();
+    for (field_name, segments, value) in mapped {
+        remove_at(&mut object, &segments);
+        let Some(value) = value else { continue };
+        if field_name == "timestamp" {
+            entry.timestamp = json_timestamp(&value, reference);
+        } else if let Some(text) = value_text(&value) {
+            set_entry_field(entry, field_name, text, reference);
+        }
+    }
+
+    let mut remaining = Vec::new();
+    flatten(String::new(), Value::Object(object), &mut remaining);
+    for (key, value) in remaining {
+        let unset = match key.as_str() {
+            // The line itself is always `raw_log`
+            "raw_log" => false,
+            "timestamp" => entry.timestamp.is_none(),
+            typed @ ("event_type" | "ip_address" | "user_id" | "level" | "message" | "method" | "path" | "query"
+                | "status" | "bytes" | "referrer" | "user_agent") => entry.field(typed).is_none(),
+            _ => true,
+        };
+        if !unset {
+            continue;
+        }
+        if key == "timestamp" {
+            entry.timestamp = json_timestamp(&value, reference);
+        } else if let Some(text) = value_text(&value) {
+            set_entry_field(entry, &key, text, reference);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str, field_map: &[(&str, &str)]) -> LogEntry {
+        let field_map = field_map.iter().map(|(field, path)| (field.to_string(), path.to_string())).collect();
+        let mut entry = LogEntry::unparsed(line);
+        apply_json_fields(&mut entry, &field_map, parse_json_object(line).unwrap(), NaiveDate::from_ymd_opt(2026, 1, 1).unwrap());
+        entry
+    }
+
+    #[test]
+    fn paths_with_and_without_prefix_map_nested_values() {
+        let entry = parse(r#"{"client": {"ip": "10.0.0.1"}, "request": {"user": "alice"}}"#, &[("ip_address", "$.client.ip"), ("user_id", "request.user")]);
+        assert_eq!(entry.ip_address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(entry.user_id.as_deref(), Some("alice"));
+        // Mapped values aren't repeated in `extra`
+        assert!(entry.extra.is_empty());
+    }
+
+    #[test]
+    fn unmapped_keys_are_flattened_into_extra() {
+        let entry = parse(r#"{"request": {"method": "GET", "tags": ["a", 1]}, "ok": true, "gone": null}"#, &[]);
+        assert_eq!(entry.extra.get("request.method").map(String::as_str), Some("GET"));
+        assert_eq!(entry.extra.get("request.tags").map(String::as_str), Some(r#"["a",1]"#));
+        assert_eq!(entry.extra.get("ok").map(String::as_str), Some("true"));
+        assert!(!entry.extra.contains_key("gone"));
+    }
+
+    #[test]
+    fn unmapped_typed_names_fill_only_unset_fields() {
+        let entry = parse(r#"{"message": "hi", "level": "WARN", "msg": "mapped", "raw_log": "spoofed"}"#, &[("message", "msg")]);
+        assert_eq!(entry.message.as_deref(), Some("mapped"));
+        assert_eq!(entry.level.as_deref(), Some("WARN"));
+        // The clashing key is dropped, since `extra` can't shadow a typed field; `raw_log` is always the line itself
+        assert!(!entry.extra.contains_key("message") && !entry.extra.contains_key("raw_log"));
+        assert!(entry.raw_log.starts_with('{'));
+    }
+
+    #[test]
+    fn numeric_timestamps_are_epoch_seconds_or_millis() {
+        let expected = DateTime::from_timestamp(1_700_000_000, 0);
+        assert_eq!(parse(r#"{"ts": 1700000000}"#, &[("timestamp", "ts")]).timestamp, expected);
+        assert_eq!(parse(r#"{"ts": 1700000000000}"#, &[("timestamp", "ts")]).timestamp, expected);
+        assert_eq!(parse(r#"{"timestamp": "2023-11-14T22:13:20Z"}"#, &[]).timestamp, expected);
+    }
+}
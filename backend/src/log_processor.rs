@@ -1,141 +1,153 @@
-use crate::models::{LogEntry, Alert, Metrics, ParsingRule};
+use crate::models::{LogEntry, Metrics, ParsingRule};
+use crate::parser_config::{ParsedLine, ParserSet};
+use crate::json_parser::apply_json_fields;
+use crate::syslog_parser::apply_syslog_fields;
+use crate::access_log_parser::apply_access_fields;
 use crate::rules_engine::RulesEngine;
-use std::sync::{Arc, Mutex};
-use chrono::{DateTime, Utc, TimeZone, NaiveDateTime};
-use regex::Regex;
-use std::collections::HashMap;
+use crate::threat_detection::ThreatDetector;
+use crate::sequential_analysis::{run_sequential_analysis, analyze_with_detector};
+use crate::parallel_analysis::run_parallel_analysis;
+use std::sync::Arc;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
 
-pub fn parse_log_content(content: String, parsing_rules: Arc<Mutex<Vec<ParsingRule>>>) -> Vec<LogEntry> {
-    let mut log_entries = Vec::new();
-    let rules_locked = parsing_rules.lock().unwrap();
+pub fn parse_log_content(content: String, parsers: &ParserSet) -> Vec<LogEntry> {
+    content.lines().map(|line| parse_log_line(line, parsers)).collect()
+}
 
-    for line in content.lines() {
-        let mut parsed_entry = LogEntry {
-            raw_log: line.to_string(),
-            timestamp: None,
-            ip_address: None,
-            user_id: None,
-            event_type: None,
-            level: None,
-            message: None,
-            extra: HashMap::new(),
-        };
+/// Parses one line with the first matching parsing rule. Lines no rule matches keep only `raw_log`.
+pub fn parse_log_line(line: &str, parsers: &ParserSet) -> LogEntry {
+    let mut parsed_entry = LogEntry::unparsed(line);
 
-        for rule in rules_locked.iter() {
-            if let Ok(regex) = Regex::new(&rule.pattern) {
-                if let Some(captures) = regex.captures(line) {
-                    for (field_name, capture_name) in &rule.field_map {
-                        if let Some(captured_value) = captures.name(capture_name).map(|m| m.as_str().to_string()) {
-                            match field_name.as_str() {
-                                "timestamp" => {
-                                    let common_formats = [
-                                        "%Y-%m-%dT%H:%M:%S%.3fZ", // RFC3339 with milliseconds
-                                        "%Y-%m-%dT%H:%M:%S%:z",    // RFC3339 with timezone
-                                        "%Y-%m-%d %H:%M:%S",      // Common YYYY-MM-DD HH:MM:SS
-                                        "%b %d %H:%M:%S",          // Syslog-like (e.g., "Jan 01 12:34:56")
-                                        "%Y/%m/%d %H:%M:%S",      // YYYY/MM/DD HH:MM:SS
-                                    ];
+    // Apply the first matching rule
+    if let Some((rule, parsed)) = parsers.first_match(line) {
+        apply_parsed_line(&mut parsed_entry, rule, parsed);
+    }
+    parsed_entry
+}
 
-                                    for format in common_formats.iter() {
-                                        if let Ok(ts) = DateTime::parse_from_str(&captured_value, format) {
-                                            parsed_entry.timestamp = Some(ts.with_timezone(&Utc));
-                                            break;
-                                        } else if let Ok(ts) = NaiveDateTime::parse_from_str(&captured_value, format) {
-                                            parsed_entry.timestamp = Some(Utc.from_utc_datetime(&ts));
-                                            break;
-                                        }
-                                    }
-                                },
-                                "ip_address" => parsed_entry.ip_address = Some(captured_value),
-                                "user_id" => parsed_entry.user_id = Some(captured_value),
-                                "event_type" => parsed_entry.event_type = Some(captured_value),
-                                "level" => parsed_entry.level = Some(captured_value),
-                                "message" => parsed_entry.message = Some(captured_value),
-                                _ => {
-                                    parsed_entry.extra.insert(field_name.clone(), captured_value);
-                                }
-                            }
-                        }
-                    }
-                    break; // Apply the first matching rule
+/// Fills the entry with what `rule` extracted from its line.
+pub fn apply_parsed_line(entry: &mut LogEntry, rule: &ParsingRule, parsed: ParsedLine) {
+    let reference = rule.year_reference();
+    match parsed {
+        ParsedLine::Captures(captures) => {
+            for (field_name, capture_name) in &rule.field_map {
+                if let Some(captured_value) = captures.name(capture_name).map(|m| m.as_str().to_string()) {
+                    set_entry_field(entry, field_name, captured_value, reference);
                 }
             }
+        },
+        ParsedLine::Json(object) => apply_json_fields(entry, &rule.field_map, object, reference),
+        ParsedLine::Syslog(message) => apply_syslog_fields(entry, &rule.field_map, message, reference),
+        ParsedLine::Access(variables) => apply_access_fields(entry, &rule.field_map, variables, reference),
+    }
+}
+
+/// Sets a `LogEntry` field by name from parsed text; names that aren't typed fields go to `extra`.
+/// Timestamps that parse in none of the known formats, and `status`/`bytes` that aren't numbers,
+/// are left unset.
+pub fn set_entry_field(entry: &mut LogEntry, field_name: &str, value: String, reference: NaiveDate) {
+    match field_name {
+        "timestamp" => entry.timestamp = parse_timestamp(&value, reference),
+        "ip_address" => entry.ip_address = Some(value),
+        "user_id" => entry.user_id = Some(value),
+        "event_type" => entry.event_type = Some(value),
+        "level" => entry.level = Some(value),
+        "message" => entry.message = Some(value),
+        "method" => entry.method = Some(value),
+        "path" => entry.path = Some(value),
+        "query" => entry.query = Some(value),
+        "status" => entry.status = value.parse().ok(),
+        "bytes" => entry.bytes = value.parse().ok(),
+        "referrer" => entry.referrer = Some(value),
+        "user_agent" => entry.user_agent = Some(value),
+        _ => {
+            entry.extra.insert(field_name.to_string(), value);
         }
-        log_entries.push(parsed_entry);
     }
-    log_entries
 }
 
+/// Parses a timestamp in any of the known formats. Formats without a year, like syslog's
+/// "Jan  1 12:34:56", get the year that puts them closest before `reference` (see
+/// `infer_year`).
+pub fn parse_timestamp(value: &str, reference: NaiveDate) -> Option<DateTime<Utc>> {
+    if let Ok(ts) = DateTime::parse_from_rfc3339(value) {
+        return Some(ts.with_timezone(&Utc));
+    }
+    let common_formats = [
+        "%Y-%m-%dT%H:%M:%S%.3fZ", // RFC3339 with milliseconds
+        "%Y-%m-%dT%H:%M:%S%:z",    // RFC3339 with timezone
+        "%Y-%m-%d %H:%M:%S",      // Common YYYY-MM-DD HH:MM:SS
+        "%Y/%m/%d %H:%M:%S",      // YYYY/MM/DD HH:MM:SS
+        "%d/%b/%Y:%H:%M:%S %z",   // Access log, e.g. 10/Oct/2000:13:55:36 -0700
+    ];
 
-use std::time::Instant;
+    for format in common_formats.iter() {
+        if let Ok(ts) = DateTime::parse_from_str(value, format) {
+            return Some(ts.with_timezone(&Utc));
+        } else if let Ok(ts) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(Utc.from_utc_datetime(&ts));
+        }
+    }
+    // Syslog-like (e.g., "Jan 01 12:34:56"), with a padded or unpadded day
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    infer_year(&value, "%b %d %H:%M:%S", reference).map(|ts| Utc.from_utc_datetime(&ts))
+}
 
-pub fn process_sequential(
-    log_entries: Vec<LogEntry>,
-    rules_engine: Arc<Mutex<RulesEngine>>,
-) -> Metrics {
-    let start_time = Instant::now();
-    let mut alerts = Vec::new();
-    let mut processed_logs_count = 0;
-    let rules_engine_locked = rules_engine.lock().unwrap();
-    for entry in &log_entries {
-        processed_logs_count += 1;
-        alerts.extend(rules_engine_locked.evaluate_log_entry(entry));
+/// Parses a `format` without a year in `reference`'s year, or the year before if that would put it
+/// more than a day after `reference`: logs are written before the file's last modification (or a
+/// configured reference date), and a day of slack covers time zones and clock skew.
+pub fn infer_year(value: &str, format: &str, reference: NaiveDate) -> Option<NaiveDateTime> {
+    let format = format!("%Y {}", format);
+    let in_year = |year: i32| NaiveDateTime::parse_from_str(&format!("{} {}", year, value), &format).ok();
+    let latest = reference + Duration::days(1);
+    match in_year(reference.year()) {
+        Some(ts) if ts.date() <= latest => Some(ts),
+        // Feb 29 falls back until a leap year
+        _ => (1..=4).find_map(|years_back| in_year(reference.year() - years_back).filter(|ts| ts.date() <= latest)),
     }
+}
+
+use std::time::Instant;
+
+/// Fills in the timing fields from the wall-clock time since `start_time`.
+pub fn with_timing(mut metrics: Metrics, start_time: Instant) -> Metrics {
     let duration = start_time.elapsed();
     let execution_time_ms = duration.as_secs_f64() * 1000.0;
-    let logs_per_second = if execution_time_ms > 0.001 {
-        (processed_logs_count as f64 / execution_time_ms) * 1000.0
+    metrics.execution_time_ms = execution_time_ms;
+    metrics.logs_per_second = if execution_time_ms > 0.001 {
+        (metrics.total_logs_processed as f64 / execution_time_ms) * 1000.0
     } else {
-        processed_logs_count as f64 / duration.as_secs_f64().max(0.000001)
+        metrics.total_logs_processed as f64 / duration.as_secs_f64().max(0.000001)
     };
-
-    Metrics {
-        total_logs_processed: processed_logs_count,
-        execution_time_ms,
-        logs_per_second,
-        alerts_generated: alerts,
-        mode: "Sequential".to_string(),
-    }
+    metrics
 }
 
-use rayon::prelude::*;
+pub fn process_sequential(
+    log_entries: Vec<LogEntry>,
+    rules_engine: Arc<RulesEngine>,
+) -> Metrics {
+    let start_time = Instant::now();
+    with_timing(run_sequential_analysis(log_entries, rules_engine), start_time)
+}
 
+/// Evaluates entries across the rayon pool against an immutable rules snapshot, without locks.
+/// Windowed detection is sharded by `partition_key`.
 pub fn process_parallel(
     log_entries: Vec<LogEntry>,
-    rules_engine: Arc<Mutex<RulesEngine>>,
+    rules_engine: Arc<RulesEngine>,
+    partition_key: &str,
 ) -> Metrics {
     let start_time = Instant::now();
-    let alerts: Vec<Alert> = log_entries.par_iter().flat_map(|entry| {
-        let rules_engine_locked = rules_engine.lock().unwrap();
-        rules_engine_locked.evaluate_log_entry(entry)
-    }).collect();
-
-    let duration = start_time.elapsed();
-    let execution_time_ms = duration.as_secs_f64() * 1000.0;
-    let logs_per_second = if execution_time_ms > 0.001 {
-        (log_entries.len() as f64 / execution_time_ms) * 1000.0
-    } else {
-        log_entries.len() as f64 / duration.as_secs_f64().max(0.000001)
-    };
-
-    Metrics {
-        total_logs_processed: log_entries.len(),
-        execution_time_ms,
-        logs_per_second,
-        alerts_generated: alerts,
-        mode: "Parallel".to_string(),
-    }
+    with_timing(run_parallel_analysis(log_entries, rules_engine, partition_key), start_time)
 }
 
-pub fn process_distributed(
+/// Feeds entries through a long-lived detector so windows span successive batches of a stream.
+pub fn process_stream(
     log_entries: Vec<LogEntry>,
-    rules_engine: Arc<Mutex<RulesEngine>>,
+    threat_detector: &mut ThreatDetector,
 ) -> Metrics {
     let start_time = Instant::now();
-    // Fall back to sequential for now, but with proper timing
-    let mut metrics = process_sequential(log_entries, rules_engine);
-    
-    metrics.mode = "Distributed".to_string();
-    metrics.execution_time_ms = start_time.elapsed().as_secs_f64() * 1000.0;
-    metrics
+    let mut metrics = analyze_with_detector(&log_entries, threat_detector);
+    metrics.mode = "Stream".to_string();
+    with_timing(metrics, start_time)
 }
@@ -1,10 +1,9 @@
 use std::fs::File;
 use std::io::{self, BufReader, BufRead};
-use std::path::Path;
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 mod models;
-mod log_parser;
 mod threat_detection;
 mod sequential_analysis;
 mod parallel_analysis;
@@ -15,143 +14,389 @@ mod rules_engine;
 mod log_processor;
 mod ai_module;
 mod parser_config;
+mod benchmark;
+mod partition;
+mod window;
+mod sequence;
+mod absence;
+mod group_store;
+mod snapshot;
+mod json_parser;
+mod syslog_parser;
+mod access_log_parser;
 
-use models::{LogEntry, Metrics};
-use log_parser::parse_log_entry;
-use parser_config::load_parsing_rules;
+use models::{Metrics, ParsingRule};
+use chrono::{DateTime, Utc};
+use parser_config::{load_parsing_rules, ParserSet};
 
 
 
 use utils::Timer;
 use rules_engine::RulesEngine;
-use log_processor::{process_sequential, process_parallel, process_distributed};
-use std::sync::{Arc, Mutex};
+use log_processor::{process_sequential, process_parallel, parse_log_line};
+use distributed_analysis::{master::{run_master, JobInput, MasterConfig}, protocol::Encoding, registry::{load_cluster_config, ClusterConfig}, security::{ClusterSecurity, TlsFiles}, worker::{run_worker, WorkerConfig}};
+use benchmark::{run_benchmark, run_codec_benchmark};
+use group_store::StateLimits;
+use server::StreamOptions;
+use partition::DEFAULT_PARTITION_KEY;
+use std::sync::{Arc, RwLock};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
-    #[clap(short, long, value_parser, default_value_t = String::from("analysis"))]
-    mode: String,
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
 
-    #[clap(long, value_parser)]
-    log_file: Option<String>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Analyze a log file once and print the alerts
+    Analyze(AnalyzeArgs),
+    /// Time sequential against parallel analysis, and compare the wire encodings
+    Benchmark(BenchmarkArgs),
+    /// Run the web server, the stream detector and the worker registry
+    Serve(ServeArgs),
+    /// Run a distributed-analysis worker
+    Worker(WorkerArgs),
+}
 
+/// The rules every command parses and detects with; the server uses the same files.
+#[derive(Args, Debug)]
+struct PipelineArgs {
     #[clap(long, value_parser, default_value_t = String::from("rules.json"))]
-    rules_file: String,
+    rules: String,
+
+    #[clap(long, value_parser, default_value_t = String::from("parsing_rules.json"))]
+    parsing_rules: String,
+}
+
+#[derive(Args, Debug)]
+struct TlsArgs {
+    /// PEM certificate for this node's end of master/worker links
+    #[clap(long, value_parser)]
+    tls_cert: Option<String>,
+
+    /// PEM private key matching --tls-cert
+    #[clap(long, value_parser)]
+    tls_key: Option<String>,
+
+    /// PEM CA bundle the peer's certificate must chain to; enables TLS on the master and mutual TLS on workers
+    #[clap(long, value_parser)]
+    tls_ca: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Engine {
+    Sequential,
+    Parallel,
+    /// Work units spread across the workers in --cluster-file, which parse the lines themselves
+    Distributed,
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeArgs {
+    #[clap(long, value_parser)]
+    log_file: String,
+
+    #[clap(long, value_enum, default_value_t = Engine::Parallel)]
+    engine: Engine,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+
+    #[clap(long, value_parser, default_value_t = String::from(DEFAULT_PARTITION_KEY))]
+    partition_key: String,
+
+    /// Cluster topology for the distributed engine
+    #[clap(long, value_parser, default_value_t = String::from("cluster.json"))]
+    cluster_file: String,
+
+    #[clap(long, value_parser, default_value_t = 5000)]
+    unit_size: usize,
+
+    #[clap(long, value_parser, default_value_t = String::from("msgpack"))]
+    wire_encoding: String,
+
+    #[clap(long)]
+    no_compression: bool,
 
-    #[clap(long, value_parser, default_value_t = 4)]
-    workers: usize,
+    #[clap(flatten)]
+    tls: TlsArgs,
 }
 
+#[derive(Args, Debug)]
+struct BenchmarkArgs {
+    #[clap(long, value_parser)]
+    log_file: String,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+
+    #[clap(long, value_parser, default_value_t = String::from(DEFAULT_PARTITION_KEY))]
+    partition_key: String,
+
+    #[clap(long, value_parser, default_value_t = 5)]
+    iterations: usize,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+
+    /// Cluster topology: static workers and the registry address
+    #[clap(long, value_parser, default_value_t = String::from("cluster.json"))]
+    cluster_file: String,
+
+    #[clap(flatten)]
+    tls: TlsArgs,
+
+    /// Most groups (e.g. source IPs) each stateful rule of the stream detector tracks; the least
+    /// recently updated group is evicted to make room
+    #[clap(long, value_parser, default_value_t = 100_000)]
+    max_keys_per_rule: usize,
+
+    /// Forget groups that have been idle for this many seconds
+    #[clap(long, value_parser)]
+    idle_key_seconds: Option<u64>,
+
+    /// File the server snapshots detector state to and restores it from at startup
+    #[clap(long, value_parser, default_value_t = String::from("detector_state.json"))]
+    state_file: String,
+
+    #[clap(long, value_parser, default_value_t = 60)]
+    snapshot_interval_seconds: u64,
+}
+
+#[derive(Args, Debug)]
+struct WorkerArgs {
+    #[clap(long, value_parser, default_value_t = 0)]
+    worker_id: usize,
+
+    /// Address the worker listens on
+    #[clap(long, value_parser, default_value_t = String::from("127.0.0.1:8081"))]
+    listen: String,
+
+    /// Address the worker registers as, when the master can't reach it at --listen
+    #[clap(long, value_parser)]
+    advertise: Option<String>,
+
+    /// Master registry the worker registers with
+    #[clap(long, value_parser)]
+    registry: Option<String>,
+
+    /// Concurrent work units the worker accepts (default: core count)
+    #[clap(long, value_parser)]
+    capacity: Option<usize>,
+
+    #[clap(flatten)]
+    tls: TlsArgs,
+}
+
+/// Name of the environment variable (also read from `.env`) holding the cluster's shared secret.
+const SHARED_SECRET_VAR: &str = "CLUSTER_SHARED_SECRET";
+
+fn tls_files(args: &TlsArgs) -> TlsFiles {
+    TlsFiles { cert_file: args.tls_cert.clone(), key_file: args.tls_key.clone(), ca_file: args.tls_ca.clone() }
+}
+
+fn shared_secret() -> Option<String> {
+    std::env::var(SHARED_SECRET_VAR).ok().filter(|secret| !secret.is_empty())
+}
+
+// A missing cluster file just means no static workers.
+fn cluster_config(path: &str) -> Result<ClusterConfig, String> {
+    if Path::new(path).exists() {
+        load_cluster_config(path)
+    } else {
+        println!("No cluster file at {}; relying on worker registration only.", path);
+        Ok(ClusterConfig::default())
+    }
+}
+
+// Lines that aren't valid UTF-8 are decoded lossily rather than dropped.
 fn read_log_file(filename: &Path) -> io::Result<Vec<String>> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
-    Ok(reader.lines().filter_map(|line| line.ok()).collect())
+    reader
+        .split(b'\n')
+        .map(|line| {
+            let line = line?;
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            Ok(String::from_utf8_lossy(line).into_owned())
+        })
+        .collect()
 }
 
-fn read_rules_file_content(filename: &Path) -> io::Result<String> {
-    let file = File::open(filename)?;
-    let reader = BufReader::new(file);
-    let mut content = String::new();
-    for line in reader.lines() {
-        content.push_str(&line?);
-        content.push('\n');
+// Year-less timestamps in a log file were written before the file was last modified, so rules
+// without a configured reference date use the file's modification date.
+fn load_parsing_rules_for(path: &str, log_file: &str) -> Result<Vec<ParsingRule>, String> {
+    let mut parsing_rules = load_parsing_rules(path)?;
+    let modified = std::fs::metadata(log_file)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("Failed to read modification time of {}: {}", log_file, e))?;
+    let modified = DateTime::<Utc>::from(modified).date_naive();
+    for rule in &mut parsing_rules {
+        rule.reference_date.get_or_insert(modified);
     }
-    Ok(content)
+    Ok(parsing_rules)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv::dotenv().ok();
-    let args = Args::parse();
-
-    match args.mode.as_str() {
-        "analysis" => {
-            let log_file = args.log_file.expect("Log file must be provided for analysis mode.");
-            println!("Loading log file: {}", log_file);
-            let log_lines = read_log_file(Path::new(&log_file))?;
-            println!("Loaded {} log entries.", log_lines.len());
-
-            println!("Loading rules file: {}", args.rules_file);
-            let rules_json = read_rules_file_content(Path::new(&args.rules_file))?;
-            let mut rules_engine = RulesEngine::new();
-            rules_engine.load_rules(&rules_json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            println!("Loaded {} rules.", rules_engine.rules.len());
-
-            let mut metrics: Metrics;
-
-            let timer = Timer::new();
-
-            match args.mode.as_str() {
-                "sequential" => {
-                    println!("Running sequential analysis...");
-                    let parsed_logs: Vec<LogEntry> = log_lines.iter()
-                        .filter_map(|line| parse_log_entry(line))
-                        .collect();
-                    metrics = process_sequential(parsed_logs, Arc::new(Mutex::new(rules_engine)));
-                    let elapsed_time_ms = timer.elapsed_millis();
-                    metrics.execution_time_ms = elapsed_time_ms;
-                    metrics.logs_per_second = (metrics.total_logs_processed as f64 / elapsed_time_ms as f64) * 1000.0;
-                },
-                "parallel" => {
-                    println!("Running parallel analysis...");
-                    let parsed_logs: Vec<LogEntry> = log_lines.iter()
-                        .filter_map(|line| parse_log_entry(line))
-                        .collect();
-                    metrics = process_parallel(parsed_logs, Arc::new(Mutex::new(rules_engine)));
-                    let elapsed_time_ms = timer.elapsed_millis();
-                    metrics.execution_time_ms = elapsed_time_ms;
-                    metrics.logs_per_second = (metrics.total_logs_processed as f64 / elapsed_time_ms as f64) * 1000.0;
-                },
-                "distributed" => {
-                    println!("Running distributed analysis with {} workers...", args.workers);
-                    let parsed_logs: Vec<LogEntry> = log_lines.iter()
-                        .filter_map(|line| parse_log_entry(line))
-                        .collect();
-                    metrics = process_distributed(parsed_logs, Arc::new(Mutex::new(rules_engine)));
-                    let elapsed_time_ms = timer.elapsed_millis();
-                    metrics.execution_time_ms = elapsed_time_ms;
-                    metrics.logs_per_second = (metrics.total_logs_processed as f64 / elapsed_time_ms as f64) * 1000.0;
-                },
-                _ => {
-                    eprintln!("Invalid analysis mode: {}. Please choose from 'sequential', 'parallel', or 'distributed'.", args.mode);
-                    std::process::exit(1);
-                }
-            }
+fn load_rules_engine(path: &str) -> Result<RulesEngine, String> {
+    let rules_json = std::fs::read_to_string(path).map_err(|e| format!("Failed to read rules file {}: {}", path, e))?;
+    let mut rules_engine = RulesEngine::new();
+    rules_engine.load_rules(&rules_json)?;
+    rules_engine.set_rules_file(path);
+    Ok(rules_engine)
+}
 
-            println!("\n--- Analysis Results ({}) ---", metrics.mode);
-            println!("Total logs processed: {}", metrics.total_logs_processed);
-            // println!("Execution time: {} ms", metrics.execution_time_ms);
-            // println!("Logs per second: {:.2}", metrics.logs_per_second);
-            println!("Total alerts generated: {}", metrics.alerts_generated.len());
-
-            if !metrics.alerts_generated.is_empty() {
-                println!("\n--- Alerts ---");
-                for alert in metrics.alerts_generated {
-                    println!("  - [{:?}] {}: {}", alert.alert_type, alert.timestamp, alert.description);
-                }
+fn print_metrics(metrics: &Metrics) {
+    println!("\n--- Analysis Results ({}) ---", metrics.mode);
+    println!("Total logs processed: {}", metrics.total_logs_processed);
+    println!("Execution time: {:.2} ms", metrics.execution_time_ms);
+    println!("Total alerts generated: {}", metrics.alerts_generated.len());
+    for (rule_id, count) in &metrics.alerts_by_rule {
+        println!("  {}: {}", rule_id, count);
+    }
+    if metrics.late_entries > 0 || metrics.untimestamped_entries > 0 {
+        println!("Entries skipped by stateful rules: {} late, {} without a timestamp", metrics.late_entries, metrics.untimestamped_entries);
+    }
+    for worker in &metrics.worker_breakdown {
+        println!(
+            "  Worker {} ({}): {} units, {} logs, {} alerts, {:.2} ms busy, {:.0} logs/sec",
+            worker.worker_id, worker.address, worker.units_completed, worker.logs_processed,
+            worker.alerts_found, worker.busy_time_ms, worker.logs_per_second
+        );
+    }
+
+    if !metrics.alerts_generated.is_empty() {
+        println!("\n--- Alerts ---");
+        for alert in &metrics.alerts_generated {
+            println!("  - [{:?}] {}: {}", alert.alert_type, alert.timestamp, alert.description);
+        }
+    }
+}
+
+// Lines are parsed with the parsing rules, exactly as uploads to the server are; the distributed
+// engine ships the raw lines and the parsing rules so the workers do the same.
+async fn analyze(args: AnalyzeArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("Loading log file: {}", args.log_file);
+    let log_lines = read_log_file(Path::new(&args.log_file))?;
+    println!("Loaded {} log lines.", log_lines.len());
+    let parsing_rules = load_parsing_rules_for(&args.pipeline.parsing_rules, &args.log_file)?;
+    let rules_engine = load_rules_engine(&args.pipeline.rules)?;
+    println!("Loaded {} rules.", rules_engine.rules().len());
+
+    let timer = Timer::new();
+    let mut metrics = match args.engine {
+        Engine::Sequential | Engine::Parallel => {
+            let parsers = ParserSet::new(parsing_rules);
+            let log_entries = log_lines.iter().map(|line| parse_log_line(line, &parsers)).collect();
+            let rules_engine = Arc::new(rules_engine);
+            if let Engine::Sequential = args.engine {
+                println!("Running sequential analysis...");
+                process_sequential(log_entries, rules_engine)
+            } else {
+                println!("Running parallel analysis...");
+                process_parallel(log_entries, rules_engine, &args.partition_key)
             }
         },
-        "server" => {
-            println!("Starting web server on 127.0.0.1:8080...");
-            let rules_engine = Arc::new(Mutex::new(RulesEngine::new()));
-
-            let parsing_rules_path = "parsing_rules.json";
-            let parsing_rules = match load_parsing_rules(parsing_rules_path) {
-                Ok(r) => r,
-                Err(e) => {
-                    log::error!("Failed to load parsing rules: {}. Exiting.", e);
-                    std::process::exit(1);
-                }
+        Engine::Distributed => {
+            let worker_addresses = cluster_config(&args.cluster_file)?.workers;
+            println!("Running distributed analysis with {} workers...", worker_addresses.len());
+            let master_config = MasterConfig {
+                unit_size: args.unit_size,
+                partition_key: args.partition_key.clone(),
+                encoding: args.wire_encoding.parse::<Encoding>()?,
+                compression: !args.no_compression,
+                security: Arc::new(ClusterSecurity::for_master(&tls_files(&args.tls), shared_secret())?),
+                ..MasterConfig::default()
             };
-
-            server::run_server(rules_engine, parsing_rules).await?;
+            run_master(JobInput::Lines { lines: log_lines, parsing_rules }, rules_engine.rules().to_vec(), worker_addresses, master_config).await?
         },
-        _ => {
-            eprintln!("Invalid mode: {}. Please choose from 'analysis' or 'server'.", args.mode);
-            std::process::exit(1);
-        }
+    };
+    // Parsing included
+    metrics.execution_time_ms = timer.elapsed_millis();
+    metrics.logs_per_second = metrics.total_logs_processed as f64 / metrics.execution_time_ms.max(0.001) * 1000.0;
+    print_metrics(&metrics);
+    Ok(())
+}
+
+fn benchmark(args: BenchmarkArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let log_lines = read_log_file(Path::new(&args.log_file))?;
+    let parsers = ParserSet::new(load_parsing_rules_for(&args.pipeline.parsing_rules, &args.log_file)?);
+    let log_entries: Vec<_> = log_lines.iter().map(|line| parse_log_line(line, &parsers)).collect();
+    println!("Loaded {} log entries from {}.", log_entries.len(), args.log_file);
+    let rules_engine = load_rules_engine(&args.pipeline.rules)?;
+
+    let report = run_benchmark(log_entries.clone(), Arc::new(rules_engine), args.iterations, &args.partition_key);
+    println!("\n--- Benchmark Results ({} logs, {} iterations, {} threads) ---", report.total_logs, report.iterations, report.threads);
+    println!("Sequential (median): {:.2} ms", report.sequential_ms);
+    println!("Parallel (median):   {:.2} ms", report.parallel_ms);
+    println!("Speedup:             {:.2}x", report.speedup());
+    if !report.alerts_match {
+        eprintln!("Warning: sequential and parallel runs produced different alert counts per rule.");
     }
 
+    let codec_reports = run_codec_benchmark(log_entries, args.iterations)?;
+    let json_bytes = codec_reports[0].frame_bytes;
+    println!("\n--- Wire Encoding (one LogChunk frame) ---");
+    for report in &codec_reports {
+        println!(
+            "{:<12} compressed={:<5} {:>12} bytes ({:>5.1}% of JSON)  encode {:>8.2} ms  decode {:>8.2} ms  {:>8.1} MB/s",
+            format!("{:?}", report.codec.encoding),
+            report.codec.compression,
+            report.frame_bytes,
+            report.frame_bytes as f64 / json_bytes as f64 * 100.0,
+            report.encode_ms,
+            report.decode_ms,
+            report.throughput_mb_per_sec(json_bytes),
+        );
+    }
+    Ok(())
+}
+
+async fn serve(args: ServeArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("Starting web server on 127.0.0.1:8080...");
+    let parsing_rules = load_parsing_rules(&args.pipeline.parsing_rules)?;
+    let rules_engine = Arc::new(RwLock::new(Arc::new(load_rules_engine(&args.pipeline.rules)?)));
+
+    let security = ClusterSecurity::for_master(&tls_files(&args.tls), shared_secret())?;
+    let cluster = cluster_config(&args.cluster_file)?;
+    let limits = StateLimits {
+        max_keys: Some(args.max_keys_per_rule),
+        idle_timeout: args.idle_key_seconds.map(|seconds| chrono::Duration::seconds(seconds as i64)),
+    };
+    let stream = StreamOptions {
+        limits,
+        state_file: PathBuf::from(&args.state_file),
+        snapshot_interval: std::time::Duration::from_secs(args.snapshot_interval_seconds.max(1)),
+    };
+    server::run_server(rules_engine, parsing_rules, cluster, Arc::new(security), stream).await?;
+    Ok(())
+}
+
+async fn worker(args: WorkerArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let security = ClusterSecurity::for_worker(&tls_files(&args.tls), shared_secret())?;
+    let worker_config = WorkerConfig {
+        worker_id: args.worker_id,
+        listen_address: args.listen,
+        advertise_address: args.advertise,
+        capacity: args.capacity,
+        registry_address: args.registry,
+    };
+    run_worker(worker_config, Arc::new(security)).await?;
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+
+    match Cli::parse().command {
+        Command::Analyze(args) => analyze(args).await,
+        Command::Benchmark(args) => benchmark(args),
+        Command::Serve(args) => serve(args).await,
+        Command::Worker(args) => worker(args).await,
+    }
+}
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::distributed_analysis::protocol::{Encoding, FrameCodec};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LogEntry {
@@ -13,10 +16,70 @@ pub struct LogEntry {
     pub user_id: Option<String>,
     pub level: Option<String>,
     pub message: Option<String>,
+    // Web request fields, set by access log parsing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Query string without the leading `?`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
     #[serde(flatten)]
     pub extra: HashMap<String, String>,
 }
 
+impl LogEntry {
+    /// An entry holding nothing but its line.
+    pub fn unparsed(line: &str) -> Self {
+        LogEntry {
+            raw_log: line.to_string(),
+            timestamp: None,
+            event_type: None,
+            ip_address: None,
+            user_id: None,
+            level: None,
+            message: None,
+            method: None,
+            path: None,
+            query: None,
+            status: None,
+            bytes: None,
+            referrer: None,
+            user_agent: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    /// Looks up a field by name as text: the typed fields first, then `extra`.
+    pub fn field(&self, name: &str) -> Option<Cow<'_, str>> {
+        let text = match name {
+            "raw_log" => Some(self.raw_log.as_str()),
+            "event_type" => self.event_type.as_deref(),
+            "ip_address" => self.ip_address.as_deref(),
+            "user_id" => self.user_id.as_deref(),
+            "level" => self.level.as_deref(),
+            "message" => self.message.as_deref(),
+            "method" => self.method.as_deref(),
+            "path" => self.path.as_deref(),
+            "query" => self.query.as_deref(),
+            "status" => return self.status.map(|status| Cow::Owned(status.to_string())),
+            "bytes" => return self.bytes.map(|bytes| Cow::Owned(bytes.to_string())),
+            "referrer" => self.referrer.as_deref(),
+            "user_agent" => self.user_agent.as_deref(),
+            _ => self.extra.get(name).map(|v| v.as_str()),
+        };
+        text.map(Cow::Borrowed)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum AlertType {
     BruteForce,
@@ -28,10 +91,16 @@ pub enum AlertType {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Alert {
     pub id: String,
+    /// Id of the rule that raised the alert.
+    #[serde(default)]
+    pub rule_id: String,
     pub timestamp: DateTime<Utc>,
     pub alert_type: AlertType,
     pub description: String,
     pub log_entry_sample: Option<LogEntry>,
+    /// Every entry that contributed, in order; set by sequence rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub related_entries: Vec<LogEntry>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -41,6 +110,77 @@ pub struct Metrics {
     pub logs_per_second: f64,
     pub alerts_generated: Vec<Alert>,
     pub mode: String, // Sequential, Parallel, Distributed
+    /// Number of alerts in `alerts_generated` per rule id.
+    #[serde(default)]
+    pub alerts_by_rule: BTreeMap<String, usize>,
+    /// Entries stateful rules skipped for arriving too far behind the newest entry seen.
+    #[serde(default)]
+    pub late_entries: u64,
+    /// Entries stateful rules skipped for lacking a timestamp.
+    #[serde(default)]
+    pub untimestamped_entries: u64,
+    /// Per-worker share of a distributed run; empty for the other modes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub worker_breakdown: Vec<WorkerMetrics>,
+}
+
+impl Metrics {
+    pub fn count_alerts_by_rule(alerts: &[Alert]) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for alert in alerts {
+            *counts.entry(alert.rule_id.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+/// How many groups the stateful rules of a `ThreatDetector` track, and how many they dropped:
+/// expired after going idle, or evicted to stay within the key budget. Also counts the entries
+/// the rules skipped because they were late or had no timestamp.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DetectorStats {
+    pub tracked_keys: usize,
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    #[serde(default)]
+    pub late_entries: u64,
+    #[serde(default)]
+    pub untimestamped_entries: u64,
+}
+
+impl DetectorStats {
+    pub fn add(&mut self, other: DetectorStats) {
+        self.tracked_keys += other.tracked_keys;
+        self.expired_keys += other.expired_keys;
+        self.evicted_keys += other.evicted_keys;
+        self.late_entries += other.late_entries;
+        self.untimestamped_entries += other.untimestamped_entries;
+    }
+}
+
+/// One worker's contribution to a distributed run.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct WorkerMetrics {
+    pub worker_id: usize,
+    pub address: String,
+    pub units_completed: usize,
+    pub logs_processed: usize,
+    pub alerts_found: usize,
+    /// Time the worker spent analyzing, summed over its units.
+    pub busy_time_ms: f64,
+    pub logs_per_second: f64,
+}
+
+/// Progress of one work unit, streamed by a worker while it analyzes the unit.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChunkProgress {
+    pub chunk_id: usize,
+    pub logs_processed: usize,
+    pub alerts_so_far: usize,
+    pub elapsed_ms: f64,
+    /// Alerts found since the previous progress frame. The final `AnalysisResult` repeats them,
+    /// so a unit that is later reassigned can be discarded as a whole.
+    pub new_alerts: Vec<Alert>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -48,9 +188,111 @@ pub enum RuleType {
     BruteForce,
     HighFrequencyRequest,
     SuspiciousIp,
+    /// Number of different values of `field` per group, e.g. distinct `user_id`s per `ip_address`.
+    DistinctCount { field: String },
+    /// Ordered `steps` for one group, all within `time_window_seconds`.
+    Sequence,
+    /// Each group must produce an entry meeting `condition` at least every `time_window_seconds`.
+    Absence,
     Custom(String),
 }
 
+impl RuleType {
+    /// Windowed rule types keep state over `time_window_seconds` in `ThreatDetector`;
+    /// everything else fires on each matching line.
+    pub fn is_windowed(&self) -> bool {
+        !matches!(self, RuleType::Custom(_))
+    }
+
+    /// What a windowed rule compares against its threshold when it sets no `aggregation`:
+    /// distinct event types for SuspiciousIp, distinct `field` values for DistinctCount,
+    /// the number of entries otherwise.
+    pub fn default_aggregation(&self) -> Aggregation {
+        match self {
+            RuleType::SuspiciousIp => Aggregation::DistinctCount { field: "event_type".to_string() },
+            RuleType::DistinctCount { field } => Aggregation::DistinctCount { field: field.clone() },
+            _ => Aggregation::Count,
+        }
+    }
+
+    /// Fields that key a windowed rule's state when the rule sets no `group_by`.
+    pub fn default_group_by(&self) -> &'static [&'static str] {
+        match self {
+            RuleType::BruteForce => &["ip_address", "user_id"],
+            RuleType::Absence => &["host"],
+            _ => &["ip_address"],
+        }
+    }
+}
+
+/// How a windowed rule's `time_window_seconds` is applied.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowKind {
+    /// The window ends at the latest entry seen for the group.
+    #[default]
+    Sliding,
+    /// Fixed, back-to-back windows aligned to the Unix epoch.
+    Tumbling,
+}
+
+/// The value computed over each window and compared against the rule's threshold.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Aggregation {
+    Count,
+    /// Number of different values of `field`.
+    DistinctCount { field: String },
+    /// Sum of `field`, for entries where it parses as a number.
+    Sum { field: String },
+}
+
+/// Conditions an entry must meet, on top of the rule's `pattern`, to count towards a windowed rule.
+/// Every part that is set must hold.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MatchCondition {
+    /// `event_type` must be one of these.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub event_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldPredicate>,
+    /// Regex on `message`, or on the raw line when no message was parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_pattern: Option<String>,
+}
+
+fn default_step_count() -> usize {
+    1
+}
+
+/// One step of a sequence rule: `count` entries meeting `condition`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SequenceStep {
+    #[serde(default)]
+    pub condition: MatchCondition,
+    #[serde(default = "default_step_count")]
+    pub count: usize,
+}
+
+/// A test on one field (see `LogEntry::field`). With no test set, the field only has to be present.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FieldPredicate {
+    pub field: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_of: Option<Vec<String>>,
+    /// Regex the value must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<String>,
+    /// Inclusive bounds on the value as a number, e.g. `"min": 500` on `status`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+/// Lateness a stateful rule tolerates when it sets no `allowed_lateness_seconds`.
+pub const DEFAULT_ALLOWED_LATENESS_SECONDS: u64 = 60;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Rule {
     pub id: String,
@@ -62,31 +304,148 @@ pub struct Rule {
     pub rule_type: RuleType,
     pub time_window_seconds: Option<u64>,
     pub threshold: Option<usize>,
+    /// Which entries count towards a windowed rule. BruteForce rules without one count
+    /// `event_type == "login_failed"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<MatchCondition>,
+    /// Fields that key the rule's windows, e.g. `["ip_address"]`; see `RuleType::default_group_by`.
+    /// Entries missing any of them are not counted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group_by: Vec<String>,
+    #[serde(default)]
+    pub window: WindowKind,
+    /// See `RuleType::default_aggregation`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<Aggregation>,
+    /// Steps of a `Sequence` rule, in order. `group_by` is the join key and `time_window_seconds`
+    /// the maximum span from the first to the last entry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<SequenceStep>,
+    /// How far behind the newest entry seen an entry may be and still count towards the rule;
+    /// see `DEFAULT_ALLOWED_LATENESS_SECONDS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_lateness_seconds: Option<u64>,
+}
+
+impl Rule {
+    pub fn aggregation(&self) -> Aggregation {
+        self.aggregation.clone().unwrap_or_else(|| self.rule_type.default_aggregation())
+    }
+
+    pub fn group_fields(&self) -> Vec<&str> {
+        if self.group_by.is_empty() {
+            self.rule_type.default_group_by().to_vec()
+        } else {
+            self.group_by.iter().map(String::as_str).collect()
+        }
+    }
+
+    pub fn allowed_lateness(&self) -> Duration {
+        Duration::seconds(self.allowed_lateness_seconds.unwrap_or(DEFAULT_ALLOWED_LATENESS_SECONDS) as i64)
+    }
+}
+
+/// How a `ParsingRule` reads a line.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParserKind {
+    /// `pattern` is matched against the line and `field_map` names its capture groups.
+    #[default]
+    Regex,
+    /// A JSON object; `field_map` holds JSON paths (see `json_parser`).
+    Json,
+    /// An RFC 5424 or RFC 3164 syslog line (see `syslog_parser`).
+    Syslog,
+    /// A web server access log line in `log_format` (see `access_log_parser`).
+    AccessLog,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ParsingRule {
     pub name: String,
+    #[serde(default)]
     pub pattern: String, // Regex pattern
-    pub field_map: HashMap<String, String>, // Maps regex capture group names to LogEntry fields
+    #[serde(default)]
+    pub field_map: HashMap<String, String>, // Maps LogEntry fields to regex capture group names (or JSON paths)
     pub default: bool, // If true, this rule is applied if no other rule matches
+    #[serde(default)]
+    pub kind: ParserKind,
+    /// Date year-less timestamps are placed closest before; today if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_date: Option<NaiveDate>,
+    /// Format of `AccessLog` rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<String>,
+}
+
+impl ParsingRule {
+    pub fn year_reference(&self) -> NaiveDate {
+        self.reference_date.unwrap_or_else(|| Utc::now().date_naive())
+    }
+}
+
+/// What a worker reports about itself during the handshake.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerCapabilities {
+    pub worker_id: usize,
+    pub cores: usize,
+    /// Concurrent sessions (and so work units) the worker accepts.
+    pub capacity: usize,
+    pub max_frame_size: u32,
+    pub encodings: Vec<Encoding>,
 }
 
+/// Frames sent from the master to a worker. Every frame gets exactly one final `MasterMessage` reply.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum WorkerMessage {
-    LogChunk(Vec<LogEntry>),
+    /// Must be the first frame of a session. `encodings` lists the master's preferences, best first;
+    /// `nonce` is the master's half of the shared-secret challenge.
+    Hello { protocol_version: u32, encodings: Vec<Encoding>, compression: bool, nonce: String },
+    /// The master's proof of the shared secret. Required as the second frame by workers that have one.
+    Authenticate { proof: String },
+    /// A complete key partition, analyzed with fresh window state. Answered by `Progress` frames
+    /// while the chunk is analyzed, then its `AnalysisResult`.
+    /// `watermark` is the newest timestamp in the whole job, which absence rules measure silence up to.
+    LogChunk { chunk_id: usize, entries: Vec<LogEntry>, watermark: Option<DateTime<Utc>> },
+    /// Like `LogChunk`, but the worker parses the lines itself with the shipped `ParsingRules`.
+    RawLogChunk { chunk_id: usize, lines: Vec<String>, watermark: Option<DateTime<Utc>> },
     Rules(Vec<Rule>),
+    ParsingRules(Vec<ParsingRule>),
     StartAnalysis,
+    /// Ends the session.
     Shutdown,
 }
 
+/// Frames a worker sends to the master's registry to announce itself. Re-sent periodically as a heartbeat;
+/// answered with a `RegistryReply` once the master has tried to reach the worker at `address`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RegistryMessage {
+    /// `proof` answers the registry's `Challenge`; see `ClusterSecurity::registration_proof`.
+    Register { protocol_version: u32, address: String, proof: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RegistryReply {
+    /// Sent as soon as a worker connects, before it registers.
+    Challenge { nonce: String },
+    /// The worker must register again within `heartbeat_interval_seconds` to stay live.
+    Registered { heartbeat_interval_seconds: u64 },
+    Rejected(String),
+}
+
+/// Frames sent from a worker back to the master.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MasterMessage {
+    /// `codec` is what both sides use for the rest of the session. `proof` is present when the
+    /// worker has a shared secret, and covers both nonces.
+    Hello { protocol_version: u32, capabilities: WorkerCapabilities, codec: FrameCodec, nonce: String, proof: Option<String> },
+    /// Sent periodically while a chunk is being analyzed; doubles as the worker's heartbeat.
+    Progress(ChunkProgress),
     AnalysisResult(Metrics),
     Error(String),
     Ack,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AiExplanation {
     pub explanation: String,
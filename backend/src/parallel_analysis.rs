@@ -1,34 +1,125 @@
-use crate::models::{LogEntry, Alert, Metrics, Rule};
-
+use crate::models::{LogEntry, Alert, DetectorStats, Metrics};
+use crate::rules_engine::RulesEngine;
 use crate::threat_detection::ThreatDetector;
 
+use crate::partition::{partition_by_key, split_by_coverage};
+
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-pub fn run_parallel_analysis(parsed_logs: Vec<LogEntry>, rules: Vec<Rule>) -> Metrics {
-    let alerts: Arc<Mutex<Vec<Alert>>> = Arc::new(Mutex::new(Vec::new()));
-    let processed_logs_count: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-    let threat_detector = Arc::new(Mutex::new(ThreatDetector::new(rules)));
+fn concat(mut left: Vec<Alert>, mut right: Vec<Alert>) -> Vec<Alert> {
+    left.append(&mut right);
+    left
+}
 
-    parsed_logs.par_iter().for_each(|log_entry| {
-        let mut detector_lock = threat_detector.lock().unwrap();
-        let mut alerts_lock = alerts.lock().unwrap();
-        let mut count_lock = processed_logs_count.lock().unwrap();
+fn merge((left_alerts, mut left_stats): (Vec<Alert>, DetectorStats), (right_alerts, right_stats): (Vec<Alert>, DetectorStats)) -> (Vec<Alert>, DetectorStats) {
+    left_stats.add(right_stats);
+    (concat(left_alerts, right_alerts), left_stats)
+}
 
-        *count_lock += 1;
-        if let Some(alert) = detector_lock.detect_threats(log_entry) {
-            alerts_lock.push(alert);
-        }
-    });
+/// Stateful rules that group by `partition_key` run on one detector per key partition, which sees
+/// all events for its keys; the others run on a single detector over every entry, so alerts match a
+/// sequential run. Each detector's watermark follows its own entries, so with entries far out of
+/// order, fewer may count as late than in a sequential run, and an entry both kinds skip is
+/// counted by each.
+pub fn run_parallel_analysis(parsed_logs: Vec<LogEntry>, rules_engine: Arc<RulesEngine>, partition_key: &str) -> Metrics {
+    // Pattern-only rules are stateless: spread entries evenly and accumulate alerts per thread
+    let pattern_alerts: Vec<Alert> = parsed_logs
+        .par_iter()
+        .fold(Vec::new, |mut thread_alerts, log_entry| {
+            thread_alerts.extend(rules_engine.evaluate_log_entry(log_entry));
+            thread_alerts
+        })
+        .reduce(Vec::new, concat);
 
-    let final_processed_logs_count = *processed_logs_count.lock().unwrap();
-    let final_alerts = alerts.lock().unwrap().clone();
+    // Absence rules judge silence against the whole input, not just a partition's own entries
+    let latest = parsed_logs.iter().filter_map(|log_entry| log_entry.timestamp).max();
 
+    // Windowed rules are stateful: each key partition keeps arrival order and owns its own detector.
+    // Rules grouped by other fields get one more detector that sees every entry.
+    let (sharded_rules, unsharded_rules) = split_by_coverage(rules_engine.rules(), partition_key);
+    let sharded_engine = if unsharded_rules.is_empty() { Arc::clone(&rules_engine) } else { Arc::new(RulesEngine::from_rules(sharded_rules)) };
+    let mut jobs: Vec<(Arc<RulesEngine>, Vec<&LogEntry>)> = partition_by_key(&parsed_logs, partition_key, rayon::current_num_threads())
+        .into_iter()
+        .map(|partition| (Arc::clone(&sharded_engine), partition))
+        .collect();
+    if !unsharded_rules.is_empty() {
+        jobs.push((Arc::new(RulesEngine::from_rules(unsharded_rules)), parsed_logs.iter().collect()));
+    }
+    let (windowed_alerts, detector_stats) = jobs
+        .into_par_iter()
+        .map(|(rules_engine, partition)| {
+            let mut threat_detector = ThreatDetector::new(rules_engine);
+            let mut alerts: Vec<Alert> = partition.into_iter().flat_map(|log_entry| threat_detector.detect_threats(log_entry)).collect();
+            if let Some(latest) = latest {
+                threat_detector.check_absences(latest);
+            }
+            alerts.extend(threat_detector.take_pending_alerts());
+            (alerts, threat_detector.stats())
+        })
+        .reduce(|| (Vec::new(), DetectorStats::default()), merge);
+
+    let alerts = concat(pattern_alerts, windowed_alerts);
     Metrics {
-        total_logs_processed: final_processed_logs_count,
+        total_logs_processed: parsed_logs.len(),
         execution_time_ms: 0.0,
         logs_per_second: 0.0,
-        alerts_generated: final_alerts,
+        alerts_by_rule: Metrics::count_alerts_by_rule(&alerts),
+        alerts_generated: alerts,
+        late_entries: detector_stats.late_entries,
+        untimestamped_entries: detector_stats.untimestamped_entries,
         mode: "Parallel".to_string(),
+        worker_breakdown: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Rule;
+    use crate::sequential_analysis::run_sequential_analysis;
+    use chrono::DateTime;
+
+    fn rule(mut rule: serde_json::Value) -> Rule {
+        let defaults = serde_json::json!({ "name": "", "pattern": "", "description": "", "alert_type": "SuspiciousIp", "enabled": true });
+        for (key, value) in defaults.as_object().unwrap() {
+            rule.as_object_mut().unwrap().entry(key.clone()).or_insert(value.clone());
+        }
+        serde_json::from_value(rule).unwrap()
+    }
+
+    fn entry(t: i64, ip: &str, user: &str, host: &str) -> LogEntry {
+        let mut entry = LogEntry::unparsed(&format!("{} {} {}", ip, user, host));
+        entry.timestamp = DateTime::from_timestamp(1_700_000_000 + t, 0);
+        entry.ip_address = Some(ip.to_string());
+        entry.user_id = Some(user.to_string());
+        entry.event_type = Some("login_failed".to_string());
+        entry.extra.insert("host".to_string(), host.to_string());
+        entry
+    }
+
+    #[test]
+    fn rules_not_grouped_by_the_partition_key_match_a_sequential_run() {
+        let rules = vec![
+            // One account from many IPs
+            rule(serde_json::json!({ "id": "spray", "rule_type": { "DistinctCount": { "field": "ip_address" } }, "group_by": ["user_id"], "time_window_seconds": 60, "threshold": 5 })),
+            // Grouped by `host` by default
+            rule(serde_json::json!({ "id": "heartbeat", "rule_type": "Absence", "time_window_seconds": 30 })),
+            // Grouped by `ip_address` and `user_id`, so covered
+            rule(serde_json::json!({ "id": "brute", "rule_type": "BruteForce", "time_window_seconds": 60, "threshold": 3 })),
+        ];
+        let mut entries: Vec<LogEntry> = (0..8).map(|i| entry(i, &format!("10.0.0.{}", i), "alice", "web-1")).collect();
+        entries.extend((0..3).map(|i| entry(10 + i, "10.0.0.9", "bob", "web-2")));
+        entries.push(entry(100, "10.0.0.1", "carol", "web-1"));
+        let rules_engine = Arc::new(RulesEngine::from_rules(rules));
+
+        let sequential = run_sequential_analysis(entries.clone(), Arc::clone(&rules_engine));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        let parallel = pool.install(|| run_parallel_analysis(entries, rules_engine, "ip_address"));
+
+        assert_eq!(sequential.alerts_by_rule.get("spray"), Some(&1));
+        assert_eq!(sequential.alerts_by_rule.get("heartbeat"), Some(&1));
+        assert_eq!(sequential.alerts_by_rule.get("brute"), Some(&1));
+        assert_eq!(parallel.alerts_by_rule, sequential.alerts_by_rule);
     }
 }
@@ -1,8 +1,15 @@
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
 use std::fs;
 use std::path::Path;
-use serde_json;
+use regex::{Captures, Regex, RegexSet};
+use serde_json::{Map, Value};
 
-use crate::models::ParsingRule;
+use crate::access_log_parser::format_regex;
+use crate::json_parser::parse_json_object;
+use crate::log_processor::{apply_parsed_line, parse_timestamp};
+use crate::models::{LogEntry, ParserKind, ParsingRule};
+use crate::syslog_parser::{parse_syslog, SyslogMessage};
 
 pub fn load_parsing_rules<P: AsRef<Path>>(path: P) -> Result<Vec<ParsingRule>, String> {
     let file_content = fs::read_to_string(path)
@@ -13,3 +20,109 @@ pub fn load_parsing_rules<P: AsRef<Path>>(path: P) -> Result<Vec<ParsingRule>, S
 
     Ok(rules)
 }
+
+/// What the first matching rule extracted from a line.
+pub enum ParsedLine<'l> {
+    Captures(Captures<'l>),
+    Json(Map<String, Value>),
+    Syslog(SyslogMessage),
+    /// Access log format variables and their captured text, in format order.
+    Access(Vec<(&'l str, &'l str)>),
+}
+
+/// Parsing rules compiled once up front. A `RegexSet` pass picks the first matching rule,
+/// and only that rule's regex is run again to extract captures. JSON and syslog rules match
+/// lines that also parse as a JSON object or a syslog message. Access log rules are regex rules
+/// whose pattern is compiled from their `log_format`.
+#[derive(Debug, Clone)]
+pub struct ParserSet {
+    rules: Vec<ParsingRule>,
+    regexes: Vec<Regex>,
+    set: RegexSet,
+}
+
+impl ParserSet {
+    pub fn new(parsing_rules: Vec<ParsingRule>) -> Self {
+        let mut rules = Vec::new();
+        let mut regexes = Vec::new();
+
+        for mut rule in parsing_rules {
+            if rule.kind == ParserKind::AccessLog {
+                rule.pattern = format_regex(rule.log_format.as_deref().unwrap_or("combined"));
+            }
+            match Regex::new(&rule.pattern) {
+                Ok(regex) => {
+                    regexes.push(regex);
+                    rules.push(rule);
+                },
+                Err(e) => eprintln!("Invalid regex pattern for parsing rule {}: {}", rule.name, e),
+            }
+        }
+
+        let set = RegexSet::new(regexes.iter().map(|r| r.as_str())).unwrap_or_else(|e| {
+            eprintln!("Failed to compile parsing rule set: {}", e);
+            RegexSet::empty()
+        });
+
+        ParserSet { rules, regexes, set }
+    }
+
+    /// Returns the raw captured text for one `LogEntry` field, and the entry's timestamp, without
+    /// building the whole entry for regex rules. `raw_log` is the line itself.
+    pub fn extract_field_and_time<'a: 'l, 'l>(&'a self, line: &'l str, field_name: &str) -> (Option<Cow<'l, str>>, Option<DateTime<Utc>>) {
+        let raw_log = (field_name == "raw_log").then_some(Cow::Borrowed(line));
+        let Some((rule, parsed)) = self.first_match(line) else {
+            return (raw_log, None);
+        };
+        match parsed {
+            ParsedLine::Captures(captures) => {
+                let capture = |field_name: &str| rule.field_map.get(field_name).and_then(|capture_name| captures.name(capture_name));
+                let field = raw_log.or_else(|| capture(field_name).map(|m| Cow::Borrowed(m.as_str())));
+                let time = capture("timestamp").and_then(|m| parse_timestamp(m.as_str(), rule.year_reference()));
+                (field, time)
+            },
+            parsed => {
+                let mut entry = LogEntry::unparsed(line);
+                apply_parsed_line(&mut entry, rule, parsed);
+                (entry.field(field_name).map(|value| Cow::Owned(value.into_owned())), entry.timestamp)
+            },
+        }
+    }
+
+    /// Returns the first rule (in file order) matching the line, along with what it extracted.
+    pub fn first_match<'a: 'l, 'l>(&'a self, line: &'l str) -> Option<(&'a ParsingRule, ParsedLine<'l>)> {
+        // Parsed at most once, by the first JSON rule whose pattern matches
+        let mut json: Option<Option<Map<String, Value>>> = None;
+        for index in self.set.matches(line).into_iter() {
+            let rule = &self.rules[index];
+            match rule.kind {
+                ParserKind::Regex => {
+                    if let Some(captures) = self.regexes[index].captures(line) {
+                        return Some((rule, ParsedLine::Captures(captures)));
+                    }
+                },
+                ParserKind::Json => {
+                    if let Some(object) = json.get_or_insert_with(|| parse_json_object(line)).take() {
+                        return Some((rule, ParsedLine::Json(object)));
+                    }
+                },
+                ParserKind::AccessLog => {
+                    let regex = &self.regexes[index];
+                    if let Some(captures) = regex.captures(line) {
+                        let variables = regex.capture_names()
+                            .flatten()
+                            .filter_map(|name| captures.name(name).map(|value| (name, value.as_str())))
+                            .collect();
+                        return Some((rule, ParsedLine::Access(variables)));
+                    }
+                },
+                ParserKind::Syslog => {
+                    if let Some(message) = parse_syslog(line, rule.year_reference()) {
+                        return Some((rule, ParsedLine::Syslog(message)));
+                    }
+                },
+            }
+        }
+        None
+    }
+}
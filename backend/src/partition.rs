@@ -0,0 +1,40 @@
+use crate::models::{LogEntry, Rule};
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Windowed rules group their state by source IP, so that is the default partition key.
+pub const DEFAULT_PARTITION_KEY: &str = "ip_address";
+
+/// Splits entries into `num_partitions` groups by the hash of `partition_key` (any `LogEntry::field`
+/// name, including `extra` keys). All entries sharing a key value land in the same partition,
+/// in their original order, so stateful detection over one partition sees every event for its keys.
+/// Entries without the key all share one partition.
+pub fn partition_by_key<E: Borrow<LogEntry>>(entries: impl IntoIterator<Item = E>, partition_key: &str, num_partitions: usize) -> Vec<Vec<E>> {
+    let num_partitions = num_partitions.max(1);
+    let mut partitions: Vec<Vec<E>> = (0..num_partitions).map(|_| Vec::new()).collect();
+    for entry in entries {
+        let partition = partition_index(entry.borrow().field(partition_key).as_deref(), num_partitions);
+        partitions[partition].push(entry);
+    }
+    partitions
+}
+
+/// Whether state for `rule` can be split by `partition_key`: stateless rules always can, stateful
+/// ones only when they group by it, so that each of their groups lies within one partition.
+pub fn covers(rule: &Rule, partition_key: &str) -> bool {
+    !rule.enabled || !rule.rule_type.is_windowed() || rule.group_fields().contains(&partition_key)
+}
+
+/// Splits `rules` into those that can run on partitions by `partition_key` and the enabled stateful
+/// rules it doesn't cover, which have to see every entry.
+pub fn split_by_coverage(rules: &[Rule], partition_key: &str) -> (Vec<Rule>, Vec<Rule>) {
+    rules.iter().cloned().partition(|rule| covers(rule, partition_key))
+}
+
+/// Partition for one key value; used directly when routing unparsed lines.
+pub fn partition_index(key: Option<&str>, num_partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % num_partitions.max(1) as u64) as usize
+}
@@ -1,25 +1,205 @@
-use crate::models::{LogEntry, Rule, Alert};
-use regex::Regex;
-use serde::{Serialize, Deserialize};
+use crate::models::{LogEntry, Rule, Alert, FieldPredicate, MatchCondition, RuleType};
+use regex::{Regex, RegexSet};
 use chrono::Utc;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Clone)]
+struct IndexedSet {
+    set: RegexSet,
+    // Index into `RulesEngine::rules` for each pattern in `set`
+    rule_indices: Vec<usize>,
+}
+
+impl IndexedSet {
+    fn build(patterns: Vec<&str>, rule_indices: Vec<usize>) -> Self {
+        match RegexSet::new(&patterns) {
+            Ok(set) => IndexedSet { set, rule_indices },
+            Err(e) => {
+                eprintln!("Failed to compile rule set: {}", e);
+                IndexedSet::default()
+            }
+        }
+    }
+}
+
+impl Default for IndexedSet {
+    fn default() -> Self {
+        IndexedSet {
+            set: RegexSet::empty(),
+            rule_indices: Vec::new(),
+        }
+    }
+}
+
+/// A `MatchCondition` with its regexes compiled.
+#[derive(Debug, Clone)]
+struct CompiledCondition {
+    event_types: Vec<String>,
+    fields: Vec<(FieldPredicate, Option<Regex>)>,
+    message: Option<Regex>,
+}
+
+impl CompiledCondition {
+    fn compile(condition: &MatchCondition) -> Result<Self, regex::Error> {
+        let fields = condition.fields
+            .iter()
+            .map(|predicate| Ok((predicate.clone(), predicate.matches.as_deref().map(Regex::new).transpose()?)))
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(CompiledCondition {
+            event_types: condition.event_types.clone(),
+            fields,
+            message: condition.message_pattern.as_deref().map(Regex::new).transpose()?,
+        })
+    }
+
+    // Keeps the behavior BruteForce rules had before conditions were configurable.
+    fn brute_force_default() -> Self {
+        CompiledCondition { event_types: vec!["login_failed".to_string()], fields: Vec::new(), message: None }
+    }
+
+    fn matches(&self, log_entry: &LogEntry) -> bool {
+        if !self.event_types.is_empty()
+            && !log_entry.event_type.as_ref().is_some_and(|event_type| self.event_types.contains(event_type)) {
+            return false;
+        }
+        if let Some(message) = &self.message {
+            if !message.is_match(log_entry.message.as_deref().unwrap_or(&log_entry.raw_log)) {
+                return false;
+            }
+        }
+        self.fields.iter().all(|(predicate, regex)| {
+            let Some(value) = log_entry.field(&predicate.field) else { return false };
+            let value = value.as_ref();
+            let number = || value.parse::<f64>().ok();
+            predicate.equals.as_ref().is_none_or(|expected| value == expected)
+                && predicate.min.is_none_or(|min| number().is_some_and(|number| number >= min))
+                && predicate.max.is_none_or(|max| number().is_some_and(|number| number <= max))
+                && predicate.one_of.as_ref().is_none_or(|allowed| allowed.iter().any(|candidate| candidate == value))
+                && regex.as_ref().is_none_or(|regex| regex.is_match(value))
+        })
+    }
+}
+
+/// Pre-compiled form of the enabled rules, rebuilt whenever the rule list changes.
+/// Pattern-only rules and windowed rules (evaluated by `ThreatDetector`) get separate sets
+/// so each line is scanned once per kind.
+#[derive(Debug, Clone, Default)]
+pub struct CompiledRules {
+    pattern: IndexedSet,
+    windowed: IndexedSet,
+    // Match conditions of windowed rules, by index into `RulesEngine::rules`
+    conditions: HashMap<usize, CompiledCondition>,
+    // Step conditions of sequence rules, by rule id
+    steps: HashMap<String, Vec<CompiledCondition>>,
+}
+
+impl CompiledRules {
+    fn compile(rules: &[Rule]) -> Self {
+        let (mut pattern_patterns, mut pattern_indices) = (Vec::new(), Vec::new());
+        let (mut windowed_patterns, mut windowed_indices) = (Vec::new(), Vec::new());
+        let mut conditions = HashMap::new();
+        let mut steps = HashMap::new();
+
+        for (index, rule) in rules.iter().enumerate() {
+            if !rule.enabled { continue; }
+
+            // Validate each pattern on its own so one bad rule doesn't disable the whole set
+            if let Err(e) = Regex::new(&rule.pattern) {
+                eprintln!("Invalid regex pattern for rule {}: {}", rule.name, e);
+                continue;
+            }
+
+            if matches!(rule.rule_type, RuleType::Sequence) {
+                if rule.time_window_seconds.is_none() || rule.steps.is_empty() {
+                    eprintln!("Rule {} is a sequence but has no time_window_seconds/steps; it will never fire", rule.name);
+                }
+                match rule.steps.iter().map(|step| CompiledCondition::compile(&step.condition)).collect() {
+                    Ok(compiled) => { steps.insert(rule.id.clone(), compiled); },
+                    Err(e) => {
+                        eprintln!("Invalid regex in sequence step for rule {}: {}", rule.name, e);
+                        continue;
+                    }
+                }
+            } else if matches!(rule.rule_type, RuleType::Absence) {
+                if rule.time_window_seconds.is_none() {
+                    eprintln!("Rule {} is an absence rule but has no time_window_seconds; it will never fire", rule.name);
+                }
+            } else if rule.rule_type.is_windowed() && (rule.time_window_seconds.is_none() || rule.threshold.is_none()) {
+                eprintln!("Rule {} is windowed but has no time_window_seconds/threshold; it will never fire", rule.name);
+            }
+
+            if rule.rule_type.is_windowed() {
+                match (&rule.condition, &rule.rule_type) {
+                    (Some(condition), _) => match CompiledCondition::compile(condition) {
+                        Ok(compiled) => { conditions.insert(index, compiled); },
+                        Err(e) => {
+                            eprintln!("Invalid regex in condition for rule {}: {}", rule.name, e);
+                            continue;
+                        }
+                    },
+                    (None, RuleType::BruteForce) => { conditions.insert(index, CompiledCondition::brute_force_default()); },
+                    (None, _) => {},
+                }
+                windowed_patterns.push(rule.pattern.as_str());
+                windowed_indices.push(index);
+            } else {
+                pattern_patterns.push(rule.pattern.as_str());
+                pattern_indices.push(index);
+            }
+        }
+
+        CompiledRules {
+            pattern: IndexedSet::build(pattern_patterns, pattern_indices),
+            windowed: IndexedSet::build(windowed_patterns, windowed_indices),
+            conditions,
+            steps,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RulesEngine {
-    pub rules: Vec<Rule>,
+    // Modified only through `load_rules`/`add_rule` so the compiled set stays in sync
+    rules: Vec<Rule>,
+    compiled: Arc<CompiledRules>,
+    // File the rules were loaded from; `add_rule` writes back to it
+    rules_file: Option<PathBuf>,
 }
 
 impl RulesEngine {
     pub fn new() -> Self {
         RulesEngine {
             rules: Vec::new(),
+            compiled: Arc::new(CompiledRules::default()),
+            rules_file: None,
         }
     }
 
+    pub fn from_rules(rules: Vec<Rule>) -> Self {
+        let mut engine = RulesEngine::new();
+        engine.rules = rules;
+        engine.recompile();
+        engine
+    }
+
+    /// Makes `save_rules` write to `path`, normally the file the rules were loaded from.
+    pub fn set_rules_file(&mut self, path: impl Into<PathBuf>) {
+        self.rules_file = Some(path.into());
+    }
+
+    /// Rule definitions, in file order.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
     pub fn load_rules(&mut self, rules_json: &str) -> Result<(), String> {
         match serde_json::from_str(rules_json) {
             Ok(rules) => {
                 self.rules = rules;
+                self.recompile();
                 Ok(())
             },
             Err(e) => Err(format!("Failed to parse rules JSON: {}", e)),
@@ -32,6 +212,7 @@ impl RulesEngine {
         rule.id = format!("rule_{:03}", next_index);
         
         self.rules.push(rule);
+        self.recompile();
         
         // Persist to file
         if let Err(e) = self.save_rules() {
@@ -39,11 +220,16 @@ impl RulesEngine {
         }
     }
 
+    fn recompile(&mut self) {
+        self.compiled = Arc::new(CompiledRules::compile(&self.rules));
+    }
+
     pub fn save_rules(&self) -> Result<(), String> {
         let json = serde_json::to_string_pretty(&self.rules)
             .map_err(|e| format!("Serialization error: {}", e))?;
         
-        let mut file = std::fs::File::create("rules.json")
+        let path = self.rules_file.as_ref().ok_or("No rules file to save to")?;
+        let mut file = std::fs::File::create(path)
             .map_err(|e| format!("File creation error: {}", e))?;
         
         use std::io::Write;
@@ -53,29 +239,46 @@ impl RulesEngine {
         Ok(())
     }
 
-    pub fn evaluate_log_entry(&self, log_entry: &LogEntry) -> Vec<Alert> {
-        let mut alerts = Vec::new();
-        for rule in &self.rules {
-            if !rule.enabled { continue; }
+    fn matching<'a>(&'a self, indexed: &'a IndexedSet, log_entry: &LogEntry) -> impl Iterator<Item = (usize, &'a Rule)> + 'a {
+        indexed.set.matches(&log_entry.raw_log)
+            .into_iter()
+            .map(move |set_index| {
+                let rule_index = indexed.rule_indices[set_index];
+                (rule_index, &self.rules[rule_index])
+            })
+    }
 
-            let re = match Regex::new(&rule.pattern) {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("Invalid regex pattern for rule {}: {}", rule.name, e);
-                    continue;
-                }
-            };
-
-            if re.is_match(&log_entry.raw_log) {
-                alerts.push(Alert {
-                    id: Uuid::new_v4().to_string(),
-                    timestamp: Utc::now(),
-                    alert_type: rule.alert_type.clone(),
-                    description: format!("Rule '{}' triggered: {}", rule.name, rule.description),
-                    log_entry_sample: Some(log_entry.clone()),
-                });
-            }
-        }
-        alerts
+    /// Windowed rules whose pattern and condition match the entry, in rule order. These only count
+    /// towards a threshold; `ThreatDetector` decides whether they fire.
+    pub fn matching_windowed_rules<'a>(&'a self, log_entry: &'a LogEntry) -> impl Iterator<Item = &'a Rule> + 'a {
+        let conditions = &self.compiled.conditions;
+        self.matching(&self.compiled.windowed, log_entry)
+            .filter(move |(index, _)| conditions.get(index).is_none_or(|condition| condition.matches(log_entry)))
+            .map(|(_, rule)| rule)
+    }
+
+    /// Indices of the steps of sequence rule `rule` whose condition the entry meets.
+    pub fn matching_sequence_steps(&self, rule: &Rule, log_entry: &LogEntry) -> Vec<usize> {
+        self.compiled.steps
+            .get(&rule.id)
+            .map(|steps| steps.iter().enumerate().filter(|(_, step)| step.matches(log_entry)).map(|(index, _)| index).collect())
+            .unwrap_or_default()
+    }
+
+    /// Evaluates the pattern-only rules, which fire on every matching line. Alerts carry the
+    /// entry's timestamp, or the time of evaluation for entries without one.
+    pub fn evaluate_log_entry(&self, log_entry: &LogEntry) -> Vec<Alert> {
+        let timestamp = log_entry.timestamp.unwrap_or_else(Utc::now);
+        self.matching(&self.compiled.pattern, log_entry)
+            .map(|(_, rule)| Alert {
+                id: Uuid::new_v4().to_string(),
+                rule_id: rule.id.clone(),
+                timestamp,
+                alert_type: rule.alert_type.clone(),
+                description: format!("Rule '{}' triggered: {}", rule.name, rule.description),
+                log_entry_sample: Some(log_entry.clone()),
+                related_entries: Vec::new(),
+            })
+            .collect()
     }
 }
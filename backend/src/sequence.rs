@@ -0,0 +1,156 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::group_store::{GroupSnapshot, GroupStore, StateLimits};
+use crate::models::{DetectorStats, LogEntry};
+use crate::window::GroupKey;
+
+// One group's progress through the sequence: the step it is waiting on and the entries matched so far.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Partial {
+    step: usize,
+    // Entries matched for `step` so far
+    step_matches: usize,
+    entries: Vec<(DateTime<Utc>, LogEntry)>,
+}
+
+/// Per-group state of one sequence rule. Entries that meet the step a group is waiting on advance
+/// it; everything else is ignored. A group's sequence must complete within `span` of its first
+/// entry: while still on the first step, older entries slide out, and a partial match that has
+/// moved past the first step is dropped once it runs out of time. Steps advance in arrival order,
+/// so a late entry only counts towards the step its group is waiting on when it arrives.
+#[derive(Debug)]
+pub struct SequenceTracker {
+    // Required number of matching entries per step
+    step_counts: Vec<usize>,
+    span: Duration,
+    partials: GroupStore<Partial>,
+}
+
+impl SequenceTracker {
+    pub fn new(step_counts: Vec<usize>, span: Duration, lateness: Duration, limits: &StateLimits) -> Self {
+        SequenceTracker {
+            step_counts: step_counts.into_iter().map(|count| count.max(1)).collect(),
+            span,
+            // A partial match can't complete once `span` has passed since its latest entry, plus
+            // `lateness` for entries that arrive late
+            partials: GroupStore::new(limits.max_keys, limits.idle_timeout(Some(span + lateness))),
+        }
+    }
+
+    /// Feeds one entry of `group` that meets the steps in `matched_steps`. Returns every entry of
+    /// the sequence, in order, when this entry completes it.
+    pub fn add(&mut self, group: GroupKey, time: DateTime<Utc>, log_entry: &LogEntry, matched_steps: &[usize]) -> Option<Vec<LogEntry>> {
+        if self.step_counts.is_empty() {
+            return None;
+        }
+        let span = self.span;
+        let partial = self.partials.entry(group.clone(), time, Partial::default);
+
+        if partial.entries.first().is_some_and(|(started, _)| time - *started > span) {
+            if partial.step == 0 {
+                partial.entries.retain(|(entry_time, _)| time - *entry_time <= span);
+                partial.step_matches = partial.entries.len();
+            } else {
+                *partial = Partial::default();
+            }
+        }
+
+        if matched_steps.contains(&partial.step) {
+            partial.entries.push((time, log_entry.clone()));
+            partial.step_matches += 1;
+            if partial.step_matches >= self.step_counts[partial.step] {
+                partial.step += 1;
+                partial.step_matches = 0;
+                if partial.step == self.step_counts.len() {
+                    let completed = self.partials.remove(&group).unwrap_or_default();
+                    return Some(completed.entries.into_iter().map(|(_, entry)| entry).collect());
+                }
+            }
+        }
+
+        if partial.entries.is_empty() {
+            self.partials.remove(&group);
+        }
+        None
+    }
+
+    pub fn stats(&self) -> DetectorStats {
+        self.partials.stats()
+    }
+
+    pub fn snapshot(&self) -> Vec<GroupSnapshot<Partial>> {
+        self.partials.snapshot()
+    }
+
+    pub fn restore(&mut self, groups: Vec<GroupSnapshot<Partial>>) {
+        self.partials.restore(groups);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAILURE: &[usize] = &[0];
+    const SUCCESS: &[usize] = &[1];
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn group(name: &str) -> GroupKey {
+        vec![name.to_string()]
+    }
+
+    // Three failures then a success, within 60s
+    fn tracker() -> SequenceTracker {
+        SequenceTracker::new(vec![3, 1], Duration::seconds(60), Duration::zero(), &StateLimits::default())
+    }
+
+    fn feed(tracker: &mut SequenceTracker, name: &str, events: &[(i64, &[usize])]) -> Vec<Option<usize>> {
+        events
+            .iter()
+            .map(|&(t, steps)| tracker.add(group(name), at(t), &LogEntry::unparsed(&t.to_string()), steps).map(|entries| entries.len()))
+            .collect()
+    }
+
+    #[test]
+    fn failures_then_success_within_the_span_fire() {
+        let mut tracker = tracker();
+        let results = feed(&mut tracker, "a", &[(0, FAILURE), (1, FAILURE), (2, FAILURE), (10, SUCCESS)]);
+        assert_eq!(results, vec![None, None, None, Some(4)]);
+    }
+
+    #[test]
+    fn sequence_expires_once_the_span_is_exceeded() {
+        let mut tracker = tracker();
+        let results = feed(&mut tracker, "a", &[(0, FAILURE), (1, FAILURE), (2, FAILURE), (61, SUCCESS)]);
+        assert_eq!(results, vec![None, None, None, None]);
+        // The expired partial match was dropped, so a later success alone doesn't complete it
+        assert_eq!(feed(&mut tracker, "a", &[(62, SUCCESS)]), vec![None]);
+    }
+
+    #[test]
+    fn first_step_slides_instead_of_expiring() {
+        let mut tracker = tracker();
+        // 0 falls out of the span by 70, leaving 50, 65 and 70 as the three failures
+        let results = feed(&mut tracker, "a", &[(0, FAILURE), (50, FAILURE), (65, FAILURE), (70, FAILURE), (80, SUCCESS)]);
+        assert_eq!(results, vec![None, None, None, None, Some(4)]);
+    }
+
+    #[test]
+    fn steps_reset_after_the_sequence_completes() {
+        let mut tracker = tracker();
+        feed(&mut tracker, "a", &[(0, FAILURE), (1, FAILURE), (2, FAILURE), (3, SUCCESS)]);
+        assert_eq!(feed(&mut tracker, "a", &[(4, SUCCESS), (5, FAILURE), (6, SUCCESS)]), vec![None, None, None]);
+    }
+
+    #[test]
+    fn groups_progress_independently() {
+        let mut tracker = tracker();
+        feed(&mut tracker, "a", &[(0, FAILURE), (1, FAILURE), (2, FAILURE)]);
+        assert_eq!(feed(&mut tracker, "b", &[(3, SUCCESS)]), vec![None]);
+        assert_eq!(feed(&mut tracker, "a", &[(4, SUCCESS)]), vec![Some(4)]);
+    }
+}
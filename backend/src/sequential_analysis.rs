@@ -1,23 +1,39 @@
-use crate::models::{LogEntry, Alert, Metrics, Rule};
+use crate::models::{LogEntry, Alert, Metrics};
+use crate::rules_engine::RulesEngine;
 use crate::threat_detection::ThreatDetector;
+use std::sync::Arc;
 
-pub fn run_sequential_analysis(parsed_logs: Vec<LogEntry>, rules: Vec<Rule>) -> Metrics {
+pub fn run_sequential_analysis(parsed_logs: Vec<LogEntry>, rules_engine: Arc<RulesEngine>) -> Metrics {
+    let mut threat_detector = ThreatDetector::new(rules_engine);
+    analyze_with_detector(&parsed_logs, &mut threat_detector)
+}
+
+/// Runs pattern and windowed rules over the entries in order, plus any absence alerts the detector
+/// raised meanwhile. The detector's window state carries over between calls, which lets a
+/// long-lived detector consume a log stream in batches.
+pub fn analyze_with_detector(parsed_logs: &[LogEntry], threat_detector: &mut ThreatDetector) -> Metrics {
     let mut alerts: Vec<Alert> = Vec::new();
-    let mut threat_detector = ThreatDetector::new(rules);
+    let rules_engine = Arc::clone(threat_detector.rules_engine());
+    let skipped_before = threat_detector.stats();
     let mut processed_logs_count = 0;
 
     for log_entry in parsed_logs {
         processed_logs_count += 1;
-        if let Some(alert) = threat_detector.detect_threats(&log_entry) {
-            alerts.push(alert);
-        }
+        alerts.extend(rules_engine.evaluate_log_entry(log_entry));
+        alerts.extend(threat_detector.detect_threats(log_entry));
     }
+    alerts.extend(threat_detector.take_pending_alerts());
+    let skipped = threat_detector.stats();
 
     Metrics {
         total_logs_processed: processed_logs_count,
         execution_time_ms: 0.0,
         logs_per_second: 0.0,
+        alerts_by_rule: Metrics::count_alerts_by_rule(&alerts),
         alerts_generated: alerts,
+        late_entries: skipped.late_entries - skipped_before.late_entries,
+        untimestamped_entries: skipped.untimestamped_entries - skipped_before.untimestamped_entries,
         mode: "Sequential".to_string(),
+        worker_breakdown: Vec::new(),
     }
 }
@@ -1,74 +1,159 @@
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use actix_web::web::Bytes;
 use actix_cors::Cors;
+use serde::Deserialize;
 
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
+use crate::group_store::StateLimits;
 use crate::models::{LogEntry, Rule, ParsingRule};
+use crate::parser_config::ParserSet;
+use crate::partition::DEFAULT_PARTITION_KEY;
 use crate::rules_engine::RulesEngine;
-use crate::log_processor::{process_sequential, process_parallel, process_distributed, parse_log_content};
+use crate::snapshot::{load_snapshot, save_snapshot};
+use crate::threat_detection::{DetectorClock, ThreatDetector};
+use crate::log_processor::{process_sequential, process_parallel, process_stream, parse_log_content};
+use crate::distributed_analysis::{master::{run_master, JobInput, MasterConfig}, progress::RunProgress, registry::{ClusterConfig, WorkerRegistry}, security::ClusterSecurity};
+use chrono::Utc;
+use std::time::Duration;
 // ai_module functions are used via crate::ai_module::prefix
 
+/// How often the stream detector checks absence rules against the wall clock.
+const ABSENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct AppState {
-    pub rules_engine: Arc<Mutex<RulesEngine>>,
-    pub parsing_rules: Arc<Mutex<Vec<ParsingRule>>>,
+    // Replaced, not mutated, when rules change, so analyses keep the rules they started with
+    pub rules_engine: Arc<RwLock<Arc<RulesEngine>>>,
+    pub parsers: Arc<ParserSet>,
+    // Window state for uploads marked as pieces of one live stream, kept across them so the stream is
+    // still detected. Absence rules run on wall-clock time here and their alerts are returned with
+    // the next such upload.
+    pub stream_detector: Arc<Mutex<ThreatDetector>>,
+    pub stream_limits: StateLimits,
+    pub worker_registry: Arc<WorkerRegistry>,
+    pub cluster_security: Arc<ClusterSecurity>,
+    // The most recent distributed run, polled by the UI while it is in progress
+    pub distributed_progress: Arc<Mutex<Option<Arc<RunProgress>>>>,
 }
 
+impl AppState {
+    /// The current rules, shared so analysis runs without holding the lock.
+    fn rules_snapshot(&self) -> Arc<RulesEngine> {
+        Arc::clone(&self.rules_engine.read().unwrap())
+    }
+
+    // State counted under a rule that changed is dropped; unchanged rules keep theirs
+    fn reset_stream_detector(&self, rules_engine: &Arc<RulesEngine>) {
+        let mut detector = self.stream_detector.lock().unwrap();
+        let snapshot = detector.snapshot();
+        *detector = stream_detector(rules_engine, self.stream_limits);
+        detector.restore(snapshot);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    // The upload continues the live stream rather than being a log of its own
+    #[serde(default)]
+    stream: bool,
+}
+
+/// Analyzes an uploaded log on its own, like `/api/analyze/sequential`; with `?stream=true` it is
+/// fed to the stream detector instead, so state and the lateness watermark carry over from earlier pieces.
 #[post("/api/logs/upload")]
-pub async fn upload_log_endpoint(log_content: Bytes, data: web::Data<AppState>) -> impl Responder {
+pub async fn upload_log_endpoint(log_content: Bytes, query: web::Query<UploadQuery>, data: web::Data<AppState>) -> impl Responder {
     let log_string = match String::from_utf8(log_content.to_vec()) {
         Ok(s) => s,
         Err(e) => return HttpResponse::BadRequest().body(format!("Invalid UTF-8 sequence: {}", e)),
     };
 
-    let log_entries = parse_log_content(log_string, Arc::clone(&data.parsing_rules));
-    let rules_engine_arc = Arc::clone(&data.rules_engine);
-    let alerts = process_sequential(log_entries, rules_engine_arc);
+    let log_entries = parse_log_content(log_string, &data.parsers);
+    let alerts = if query.stream {
+        process_stream(log_entries, &mut data.stream_detector.lock().unwrap())
+    } else {
+        process_sequential(log_entries, data.rules_snapshot())
+    };
+    HttpResponse::Ok().json(alerts)
+}
+
+
+/// Absence alerts the stream detector raised since the last stream upload or poll.
+#[get("/api/logs/pending-alerts")]
+pub async fn pending_alerts_endpoint(data: web::Data<AppState>) -> impl Responder {
+    let alerts = data.stream_detector.lock().unwrap().take_pending_alerts();
     HttpResponse::Ok().json(alerts)
 }
 
 
+/// Size of the stream detector's per-group state and how many groups it expired or evicted.
+#[get("/api/detector/stats")]
+pub async fn detector_stats_endpoint(data: web::Data<AppState>) -> impl Responder {
+    let stats = data.stream_detector.lock().unwrap().stats();
+    HttpResponse::Ok().json(stats)
+}
+
 #[post("/api/rules/load")]
 pub async fn load_rules_endpoint(rules_json: web::Json<String>, data: web::Data<AppState>) -> impl Responder {
-    let mut rules_engine = data.rules_engine.lock().unwrap();
-    match rules_engine.load_rules(&rules_json.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("Rules loaded successfully"),
+    let mut rules_engine = data.rules_engine.write().unwrap();
+    match Arc::make_mut(&mut rules_engine).load_rules(&rules_json.into_inner()) {
+        Ok(_) => {
+            data.reset_stream_detector(&rules_engine);
+            HttpResponse::Ok().body("Rules loaded successfully")
+        },
         Err(e) => HttpResponse::InternalServerError().body(format!("Failed to load rules: {}", e)),
     }
 }
 
 #[get("/api/rules")]
 pub async fn get_rules_endpoint(data: web::Data<AppState>) -> impl Responder {
-    let rules_engine = data.rules_engine.lock().unwrap();
-    HttpResponse::Ok().json(&rules_engine.rules)
+    let rules_engine = data.rules_engine.read().unwrap();
+    HttpResponse::Ok().json(rules_engine.rules())
 }
 
 #[post("/api/rules/add")]
 pub async fn add_rule_endpoint(rule: web::Json<Rule>, data: web::Data<AppState>) -> impl Responder {
-    let mut rules_engine = data.rules_engine.lock().unwrap();
-    rules_engine.add_rule(rule.into_inner());
+    let mut rules_engine = data.rules_engine.write().unwrap();
+    Arc::make_mut(&mut rules_engine).add_rule(rule.into_inner());
+    data.reset_stream_detector(&rules_engine);
     HttpResponse::Ok().body("Rule added successfully")
 }
 
 #[post("/api/analyze/sequential")]
 pub async fn analyze_sequential_endpoint(log_entries: web::Json<Vec<LogEntry>>, data: web::Data<AppState>) -> impl Responder {
-    let rules_engine_arc = Arc::clone(&data.rules_engine);
-    let alerts = process_sequential(log_entries.into_inner(), rules_engine_arc);
+    let alerts = process_sequential(log_entries.into_inner(), data.rules_snapshot());
     HttpResponse::Ok().json(alerts)
 }
 
 #[post("/api/analyze/parallel")]
 pub async fn analyze_parallel_endpoint(log_entries: web::Json<Vec<LogEntry>>, data: web::Data<AppState>) -> impl Responder {
-    let rules_engine_arc = Arc::clone(&data.rules_engine);
-    let alerts = process_parallel(log_entries.into_inner(), rules_engine_arc);
+    let alerts = process_parallel(log_entries.into_inner(), data.rules_snapshot(), DEFAULT_PARTITION_KEY);
     HttpResponse::Ok().json(alerts)
 }
 
 #[post("/api/analyze/distributed")]
 pub async fn analyze_distributed_endpoint(log_entries: web::Json<Vec<LogEntry>>, data: web::Data<AppState>) -> impl Responder {
-    let rules_engine_arc = Arc::clone(&data.rules_engine);
-    let alerts = process_distributed(log_entries.into_inner(), rules_engine_arc);
-    HttpResponse::Ok().json(alerts)
+    let rules = data.rules_snapshot().rules().to_vec();
+    let progress = Arc::new(RunProgress::default());
+    *data.distributed_progress.lock().unwrap() = Some(Arc::clone(&progress));
+    let config = MasterConfig { security: Arc::clone(&data.cluster_security), progress, ..MasterConfig::default() };
+    match run_master(JobInput::Entries(log_entries.into_inner()), rules, data.worker_registry.live_addresses(), config).await {
+        Ok(metrics) => HttpResponse::Ok().json(metrics),
+        Err(e) => HttpResponse::ServiceUnavailable().body(format!("Distributed analysis failed: {}", e)),
+    }
+}
+
+#[get("/api/analyze/distributed/progress")]
+pub async fn distributed_progress_endpoint(data: web::Data<AppState>) -> impl Responder {
+    match data.distributed_progress.lock().unwrap().as_ref() {
+        Some(progress) => HttpResponse::Ok().json(progress.snapshot()),
+        None => HttpResponse::NotFound().body("No distributed analysis has been started"),
+    }
+}
+
+#[get("/api/cluster/status")]
+pub async fn cluster_status_endpoint(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.worker_registry.status())
 }
 
 // ... imports
@@ -97,22 +182,79 @@ pub async fn generate_rule_endpoint(req: web::Json<serde_json::Value>) -> impl R
     }
 }
 
-pub async fn run_server(rules_engine: Arc<Mutex<RulesEngine>>, parsing_rules: Vec<ParsingRule>) -> std::io::Result<()> {
-    // Load rules from rules.json at startup
-    let rules_path = "rules.json";
-    let rules_content = std::fs::read_to_string(rules_path)
-        .expect(&format!("Failed to read rules file: {}", rules_path));
-    {
-        let mut rules_engine_locked = rules_engine.lock().unwrap();
-        rules_engine_locked.load_rules(&rules_content)
-            .expect("Failed to load rules from JSON");
+/// Settings for the long-lived detector behind `/api/logs/upload?stream=true`.
+pub struct StreamOptions {
+    pub limits: StateLimits,
+    /// Where detector state is snapshotted and reloaded from at startup
+    pub state_file: PathBuf,
+    /// How often state is snapshotted while running; a final snapshot is taken on shutdown
+    pub snapshot_interval: Duration,
+}
+
+fn stream_detector(rules_engine: &Arc<RulesEngine>, limits: StateLimits) -> ThreatDetector {
+    ThreatDetector::with_clock(Arc::clone(rules_engine), DetectorClock::WallClock).with_limits(limits)
+}
+
+// The snapshot is taken under the lock; serializing and writing it happen outside.
+async fn save_stream_state(detector: &Mutex<ThreatDetector>, state_file: &Path) {
+    let snapshot = detector.lock().unwrap().snapshot();
+    let state_file = state_file.to_path_buf();
+    match tokio::task::spawn_blocking(move || save_snapshot(&state_file, &snapshot)).await {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => eprintln!("Failed to snapshot detector state: {}", e),
+        Err(e) => eprintln!("Detector snapshot task failed: {}", e),
     }
+}
+
+/// Serves the API with `rules_engine` as loaded at startup and `parsing_rules` for uploads.
+pub async fn run_server(rules_engine: Arc<RwLock<Arc<RulesEngine>>>, parsing_rules: Vec<ParsingRule>, cluster: ClusterConfig, cluster_security: Arc<ClusterSecurity>, stream: StreamOptions) -> std::io::Result<()> {
+    let stream_detector = {
+        let mut detector = stream_detector(&rules_engine.read().unwrap(), stream.limits);
+        match load_snapshot(&stream.state_file) {
+            Ok(Some(snapshot)) => {
+                let taken_at = snapshot.taken_at;
+                let (restored, dropped) = detector.restore(snapshot);
+                println!("Restored detector state of {} rules from {} (taken {}); dropped {} for changed rules.", restored, stream.state_file.display(), taken_at, dropped);
+            },
+            Ok(None) => {},
+            Err(e) => eprintln!("Ignoring detector state: {}", e),
+        }
+        Arc::new(Mutex::new(detector))
+    };
+    let stream_limits = stream.limits;
+
+    let snapshot_detector = Arc::clone(&stream_detector);
+    let state_file = stream.state_file.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(stream.snapshot_interval);
+        ticker.tick().await; // The first tick completes immediately
+        loop {
+            ticker.tick().await;
+            save_stream_state(&snapshot_detector, &state_file).await;
+        }
+    });
 
-    let parsing_rules_arc = Arc::new(Mutex::new(parsing_rules));
+    // Streams are silent between uploads, so absence rules need their own clock
+    let absence_detector = Arc::clone(&stream_detector);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(ABSENCE_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            absence_detector.lock().unwrap().check_absences(Utc::now());
+        }
+    });
+
+    let parsers = Arc::new(ParserSet::new(parsing_rules));
+    let worker_registry = Arc::new(WorkerRegistry::new(&cluster));
+    let distributed_progress = Arc::new(Mutex::new(None));
+    let registry_config = MasterConfig { security: Arc::clone(&cluster_security), ..MasterConfig::default() };
+    Arc::clone(&worker_registry).start(cluster.registry_address.clone(), registry_config).await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
 
     // Initialize logger
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let shutdown_detector = Arc::clone(&stream_detector);
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin() // Temporarily allow any origin for debugging
@@ -126,7 +268,12 @@ pub async fn run_server(rules_engine: Arc<Mutex<RulesEngine>>, parsing_rules: Ve
             .wrap(cors)
             .app_data(web::Data::new(AppState { 
                 rules_engine: Arc::clone(&rules_engine),
-                parsing_rules: Arc::clone(&parsing_rules_arc),
+                parsers: Arc::clone(&parsers),
+                stream_detector: Arc::clone(&stream_detector),
+                stream_limits,
+                worker_registry: Arc::clone(&worker_registry),
+                cluster_security: Arc::clone(&cluster_security),
+                distributed_progress: Arc::clone(&distributed_progress),
             }))
             .service(load_rules_endpoint)
             .service(get_rules_endpoint)
@@ -134,11 +281,20 @@ pub async fn run_server(rules_engine: Arc<Mutex<RulesEngine>>, parsing_rules: Ve
             .service(analyze_sequential_endpoint)
             .service(analyze_parallel_endpoint)
             .service(analyze_distributed_endpoint)
+            .service(distributed_progress_endpoint)
+            .service(cluster_status_endpoint)
             .service(explain_alert_endpoint)
             .service(generate_rule_endpoint)
             .service(upload_log_endpoint)
+            .service(pending_alerts_endpoint)
+            .service(detector_stats_endpoint)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
-    .await
+    .await?;
+
+    // Graceful shutdown (e.g. SIGINT/SIGTERM): keep in-flight state for the next start
+    save_stream_state(&shutdown_detector, &stream.state_file).await;
+    println!("Saved detector state to {}.", stream.state_file.display());
+    Ok(())
 }
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::absence::LastSeen;
+use crate::group_store::GroupSnapshot;
+use crate::models::{Alert, Rule};
+use crate::sequence::Partial;
+use crate::window::GroupWindow;
+
+/// Bumped whenever the snapshot layout or the meaning of saved state (e.g. value hashes) changes;
+/// snapshots of another version are ignored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A `ThreatDetector`'s per-rule state, saved so detection carries on across restarts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectorSnapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    /// Newest entry time the detector had seen, which its rules' watermarks trail.
+    #[serde(default)]
+    pub latest_event: Option<DateTime<Utc>>,
+    pub rules: Vec<RuleSnapshot>,
+    /// Absence alerts raised but not yet returned to a client.
+    #[serde(default)]
+    pub pending_alerts: Vec<Alert>,
+}
+
+/// One rule's state, tagged with the fingerprint of the rule it was built under.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuleSnapshot {
+    pub rule_id: String,
+    pub fingerprint: String,
+    pub state: RuleState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RuleState {
+    Window(Vec<GroupSnapshot<GroupWindow>>),
+    Sequence(Vec<GroupSnapshot<Partial>>),
+    Absence(Vec<GroupSnapshot<LastSeen>>),
+}
+
+/// Hash of the rule's full definition; saved state is only restored into an identical rule.
+pub fn rule_fingerprint(rule: &Rule) -> String {
+    let definition = serde_json::to_vec(rule).unwrap_or_default();
+    Sha256::digest(&definition).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes the snapshot next to `path` and renames it into place, so a crash mid-write leaves
+/// the previous snapshot intact.
+pub fn save_snapshot(path: &Path, snapshot: &DetectorSnapshot) -> Result<(), String> {
+    let json = serde_json::to_vec(snapshot).map_err(|e| format!("Serialization error: {}", e))?;
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, json).map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    std::fs::rename(&temp_path, path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+/// Reads a snapshot written by `save_snapshot`; `None` if there is none yet.
+pub fn load_snapshot(path: &Path) -> Result<Option<DetectorSnapshot>, String> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    // Check the version before the layout it governs
+    #[derive(Deserialize)]
+    struct Versioned {
+        version: u32,
+    }
+    let Versioned { version } = serde_json::from_slice(&json)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("{} has snapshot version {}, expected {}", path.display(), version, SNAPSHOT_VERSION));
+    }
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
@@ -0,0 +1,280 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::log_processor::{infer_year, set_entry_field};
+use crate::models::LogEntry;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news",
+    "uucp", "cron", "authpriv", "ftp", "ntp", "security", "console", "solaris-cron",
+    "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+
+// Severity codes 0-7 as `LogEntry.level` values
+const SEVERITIES: [&str; 8] = ["EMERGENCY", "ALERT", "CRITICAL", "ERROR", "WARNING", "NOTICE", "INFO", "DEBUG"];
+
+/// The parts of one syslog line. Everything but the message is optional: RFC 3164 lines may lack
+/// the priority, and RFC 5424 writes `-` for absent values.
+#[derive(Debug, Default)]
+pub struct SyslogMessage {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub facility: Option<&'static str>,
+    pub severity: Option<&'static str>,
+    pub host: Option<String>,
+    pub app_name: Option<String>,
+    pub pid: Option<String>,
+    pub msg_id: Option<String>,
+    /// Structured-data params keyed `sd-id.param-name`, e.g. `exampleSDID@32473.iut`.
+    pub structured_data: Vec<(String, String)>,
+    pub message: String,
+}
+
+/// Parses an RFC 5424 line (`<PRI>1 TIMESTAMP HOST APP PROCID MSGID SD MSG`) or, failing that, a
+/// BSD RFC 3164 one (`[<PRI>]Mmm dd hh:mm:ss HOST TAG[PID]: MSG`). RFC 3164 timestamps carry no
+/// year, so they get the one that puts them closest before `reference`.
+pub fn parse_syslog(line: &str, reference: NaiveDate) -> Option<SyslogMessage> {
+    let (priority, rest) = match split_priority(line) {
+        Some((priority, rest)) => (Some(priority), rest),
+        None => (None, line),
+    };
+    let mut message = match rest.strip_prefix("1 ") {
+        Some(rest) if priority.is_some() => parse_rfc5424(rest)?,
+        _ => parse_rfc3164(rest, reference)?,
+    };
+    if let Some(priority) = priority {
+        message.facility = FACILITIES.get(usize::from(priority / 8)).copied();
+        message.severity = Some(SEVERITIES[usize::from(priority % 8)]);
+    }
+    Some(message)
+}
+
+// `<PRI>` is one to three digits, at most 191 (local7.debug)
+fn split_priority(line: &str) -> Option<(u8, &str)> {
+    let (digits, rest) = line.strip_prefix('<')?.split_once('>')?;
+    if digits.is_empty() || digits.len() > 3 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let priority: u8 = digits.parse().ok()?;
+    (priority <= 191).then_some((priority, rest))
+}
+
+// `-` is RFC 5424's NILVALUE
+fn nil_or(value: &str) -> Option<String> {
+    (value != "-").then(|| value.to_string())
+}
+
+fn next_token<'l>(rest: &mut &'l str) -> Option<&'l str> {
+    let trimmed = rest.trim_start_matches(' ');
+    if trimmed.is_empty() {
+        return None;
+    }
+    let (token, remainder) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+    *rest = remainder;
+    Some(token)
+}
+
+fn parse_rfc5424(mut rest: &str) -> Option<SyslogMessage> {
+    let timestamp = next_token(&mut rest)?;
+    let host = next_token(&mut rest)?;
+    let app_name = next_token(&mut rest)?;
+    let pid = next_token(&mut rest)?;
+    let msg_id = next_token(&mut rest)?;
+    let rest = rest.trim_start_matches(' ');
+    let (structured_data, rest) = match rest.strip_prefix('-') {
+        Some(rest) => (Vec::new(), rest),
+        None => parse_structured_data(rest)?,
+    };
+    let message = rest.strip_prefix(' ').unwrap_or(rest);
+    Some(SyslogMessage {
+        timestamp: DateTime::parse_from_rfc3339(timestamp).ok().map(|ts| ts.with_timezone(&Utc)),
+        host: nil_or(host),
+        app_name: nil_or(app_name),
+        pid: nil_or(pid),
+        msg_id: nil_or(msg_id),
+        structured_data,
+        // Drop the UTF-8 byte order mark RFC 5424 allows before the message
+        message: message.strip_prefix('\u{feff}').unwrap_or(message).to_string(),
+        ..SyslogMessage::default()
+    })
+}
+
+// One or more `[sd-id name="value" ...]` elements; values escape `"`, `\` and `]` with a backslash.
+fn parse_structured_data(mut rest: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut params = Vec::new();
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element.find([' ', ']'])?;
+        let id = &element[..id_end];
+        rest = &element[id_end..];
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if let Some(after) = rest.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+            let (name, after) = rest.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()? {
+                        (_, escaped @ ('"' | '\\' | ']')) => value.push(escaped),
+                        (_, other) => {
+                            value.push('\\');
+                            value.push(other);
+                        },
+                    },
+                    (index, '"') => break index,
+                    (_, c) => value.push(c),
+                }
+            };
+            params.push((format!("{}.{}", id, name), value));
+            rest = &after[end + 1..];
+        }
+    }
+    Some((params, rest))
+}
+
+fn parse_rfc3164(rest: &str, reference: NaiveDate) -> Option<SyslogMessage> {
+    // "Mmm dd hh:mm:ss" with a space-padded day, or the RFC 3339 stamp rsyslog can be set to write
+    let (timestamp, mut rest) = match rest.get(..15).and_then(|stamp| infer_year(stamp, "%b %e %H:%M:%S", reference)) {
+        Some(ts) => (Utc.from_utc_datetime(&ts), &rest[15..]),
+        None => {
+            let (stamp, rest) = rest.split_once(' ')?;
+            (DateTime::parse_from_rfc3339(stamp).ok()?.with_timezone(&Utc), rest)
+        },
+    };
+
+    // The hostname is optional in practice (e.g. local /dev/log senders); a tag ends in `:` or `[pid]:`
+    let mut host = None;
+    let mut tag_token = next_token(&mut rest);
+    if let Some(token) = tag_token {
+        if !token.ends_with(':') && !token.contains('[') {
+            host = Some(token.to_string());
+            tag_token = next_token(&mut rest);
+        }
+    }
+
+    let mut message = SyslogMessage { timestamp: Some(timestamp), host, ..SyslogMessage::default() };
+    match tag_token.and_then(|token| token.strip_suffix(':')) {
+        Some(tag) => {
+            match tag.strip_suffix(']').and_then(|tag| tag.split_once('[')) {
+                Some((app_name, pid)) => {
+                    message.app_name = Some(app_name.to_string());
+                    message.pid = Some(pid.to_string());
+                },
+                None => message.app_name = Some(tag.to_string()),
+            }
+            message.message = rest.trim_start_matches(' ').to_string();
+        },
+        // No tag: the rest of the line, starting at that token, is the message
+        None => {
+            message.message = match tag_token {
+                Some(token) => format!("{} {}", token, rest).trim_end().to_string(),
+                None => String::new(),
+            };
+        },
+    }
+    Some(message)
+}
+
+/// Fills the entry from a syslog message: the severity becomes `level`, the text `message`, and
+/// facility, host, app name, pid, message id and structured-data params go to `extra`. `field_map`
+/// then copies any of those by name into another field, e.g. `"event_type": "app_name"`.
+pub fn apply_syslog_fields(entry: &mut LogEntry, field_map: &HashMap<String, String>, message: SyslogMessage, reference: NaiveDate) {
+    entry.timestamp = message.timestamp;
+    entry.level = message.severity.map(str::to_string);
+    entry.message = Some(message.message);
+    let named = [
+        ("facility", message.facility.map(str::to_string)),
+        ("host", message.host),
+        ("app_name", message.app_name),
+        ("pid", message.pid),
+        ("msg_id", message.msg_id),
+    ];
+    for (name, value) in named {
+        if let Some(value) = value {
+            entry.extra.insert(name.to_string(), value);
+        }
+    }
+    entry.extra.extend(message.structured_data);
+
+    for (field_name, source) in field_map {
+        if let Some(value) = entry.field(source).map(Cow::into_owned) {
+            set_entry_field(entry, field_name, value, reference);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn timestamp(text: &str) -> Option<DateTime<Utc>> {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").ok().map(|ts| Utc.from_utc_datetime(&ts))
+    }
+
+    #[test]
+    fn rfc5424_structured_data_unescapes_values() {
+        let line = r#"<165>1 2003-10-11T22:14:15.003Z host app 42 ID47 [ex@32473 a="say \"hi\"" b="x\]y\\z" c="\n"][other@1 d=""] text"#;
+        let message = parse_syslog(line, date(2026, 1, 1)).unwrap();
+        assert_eq!((message.facility, message.severity), (Some("local4"), Some("NOTICE")));
+        assert_eq!(message.msg_id.as_deref(), Some("ID47"));
+        assert_eq!(
+            message.structured_data,
+            vec![
+                ("ex@32473.a".to_string(), r#"say "hi""#.to_string()),
+                ("ex@32473.b".to_string(), r"x]y\z".to_string()),
+                // Only `"`, `\` and `]` are escapable; other backslashes are kept
+                ("ex@32473.c".to_string(), r"\n".to_string()),
+                ("other@1.d".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(message.message, "text");
+    }
+
+    #[test]
+    fn rfc5424_nil_values_are_unset() {
+        let message = parse_syslog("<14>1 - - - - - -", date(2026, 1, 1)).unwrap();
+        assert!(message.timestamp.is_none() && message.host.is_none() && message.app_name.is_none() && message.pid.is_none());
+        assert!(message.structured_data.is_empty());
+    }
+
+    #[test]
+    fn priority_must_be_in_range() {
+        let message = parse_syslog("<191>Oct 11 22:14:15 host app: text", date(2026, 1, 1)).unwrap();
+        assert_eq!((message.facility, message.severity), (Some("local7"), Some("DEBUG")));
+        let message = parse_syslog("<0>Oct 11 22:14:15 host app: text", date(2026, 1, 1)).unwrap();
+        assert_eq!((message.facility, message.severity), (Some("kern"), Some("EMERGENCY")));
+        assert!(parse_syslog("<192>Oct 11 22:14:15 host app: text", date(2026, 1, 1)).is_none());
+        assert!(parse_syslog("<1234>Oct 11 22:14:15 host app: text", date(2026, 1, 1)).is_none());
+    }
+
+    #[test]
+    fn rfc3164_tag_pid_and_optional_host() {
+        let message = parse_syslog("Dec 31 23:59:59 web1 sshd[4242]: Failed password", date(2026, 1, 1)).unwrap();
+        assert_eq!((message.host.as_deref(), message.app_name.as_deref(), message.pid.as_deref()), (Some("web1"), Some("sshd"), Some("4242")));
+        assert_eq!(message.message, "Failed password");
+        assert!(message.severity.is_none());
+        let message = parse_syslog("Feb  3 08:00:01 CRON[99]: (root) CMD", date(2026, 3, 1)).unwrap();
+        assert_eq!((message.host, message.app_name.as_deref()), (None, Some("CRON")));
+    }
+
+    #[test]
+    fn rfc3164_year_is_inferred_across_a_year_boundary() {
+        let reference = date(2026, 1, 2);
+        let year_of = |line: &str| parse_syslog(line, reference).unwrap().timestamp;
+        assert_eq!(year_of("Dec 31 23:59:59 host app: x"), timestamp("2025-12-31 23:59:59"));
+        assert_eq!(year_of("Jan  2 10:00:00 host app: x"), timestamp("2026-01-02 10:00:00"));
+        // A day of slack after the reference date for time zones and clock skew
+        assert_eq!(year_of("Jan  3 10:00:00 host app: x"), timestamp("2026-01-03 10:00:00"));
+        assert_eq!(year_of("Jan  4 10:00:00 host app: x"), timestamp("2025-01-04 10:00:00"));
+        // Feb 29 goes back to the last leap year
+        assert_eq!(year_of("Feb 29 10:00:00 host app: x"), timestamp("2024-02-29 10:00:00"));
+    }
+}
@@ -1,142 +1,330 @@
-use crate::models::{LogEntry, Alert, AlertType, Rule, RuleType};
-use chrono::{DateTime, Utc, Duration};
+use crate::absence::AbsenceTracker;
+use crate::group_store::StateLimits;
+use crate::models::{LogEntry, Alert, AlertType, DetectorStats, Rule, RuleType};
+use crate::rules_engine::RulesEngine;
+use crate::sequence::SequenceTracker;
+use crate::snapshot::{rule_fingerprint, DetectorSnapshot, RuleSnapshot, RuleState, SNAPSHOT_VERSION};
+use crate::window::{GroupKey, WindowAggregator};
+use chrono::{DateTime, Duration, Utc};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::Arc;
 use uuid::Uuid;
 
+/// The clock absence rules are judged by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectorClock {
+    /// Entry timestamps: time advances with the newest entry seen, and silence is judged once
+    /// the rule's allowed lateness has passed. For batch analysis.
+    EventTime,
+    /// Arrival time, with silence checked by `check_absences(Utc::now())`. For live streams.
+    WallClock,
+}
+
+/// Stateful evaluation of the windowed rules (`BruteForce`, `HighFrequencyRequest`, `SuspiciousIp`,
+/// `DistinctCount`), sequence rules and absence rules. A rule's `pattern` and `condition` must
+/// match before the entry counts towards its window, sequence or heartbeat, and each rule keeps
+/// its own state per value of its group fields.
+///
+/// Rules work on event time. An entry more than the rule's `allowed_lateness` behind the newest
+/// entry seen (the rule's watermark) is skipped, as is an entry without a timestamp; both are
+/// counted in `stats`. Alerts carry the time of the entry that raised them.
 pub struct ThreatDetector {
-    rules: Vec<Rule>,
-    // State for brute-force detection: (ip_address, user_id) -> (failed_attempts, last_attempt_time)
-    brute_force_attempts: HashMap<(String, String), (usize, DateTime<Utc>)>, 
-    // State for high-frequency request detection: ip_address -> (request_count, last_request_time)
-    high_frequency_requests: HashMap<String, (usize, DateTime<Utc>)>, 
-    // State for suspicious IP behavior: ip_address -> (event_counts, last_event_time)
-    suspicious_ip_behavior: HashMap<String, (HashMap<String, usize>, DateTime<Utc>)>, 
+    rules_engine: Arc<RulesEngine>,
+    clock: DetectorClock,
+    limits: StateLimits,
+    // Window state per rule id
+    windows: HashMap<String, WindowAggregator>,
+    // Sequence state per rule id
+    sequences: HashMap<String, SequenceTracker>,
+    // Heartbeat state per rule id
+    absences: HashMap<String, AbsenceTracker>,
+    // Absence alerts raised since the last `take_pending_alerts`; they aren't tied to any one entry
+    pending_alerts: Vec<Alert>,
+    // Newest entry time seen; the rules' watermarks trail it by their allowed lateness
+    latest_event: Option<DateTime<Utc>>,
+    late_entries: u64,
+    untimestamped_entries: u64,
+}
+
+// The entry's values for the rule's group fields, or `None` if it lacks one of them.
+fn group_key(log_entry: &LogEntry, rule: &Rule) -> Option<GroupKey> {
+    rule.group_fields().iter().map(|field| log_entry.field(field).map(Cow::into_owned)).collect()
+}
+
+fn new_window(rule: &Rule, time_window_seconds: u64, limits: &StateLimits) -> WindowAggregator {
+    WindowAggregator::new(rule.window, Duration::seconds(time_window_seconds as i64), rule.aggregation(), rule.allowed_lateness(), limits)
+}
+
+fn new_sequence(rule: &Rule, time_window_seconds: u64, limits: &StateLimits) -> SequenceTracker {
+    let step_counts = rule.steps.iter().map(|step| step.count).collect();
+    SequenceTracker::new(step_counts, Duration::seconds(time_window_seconds as i64), rule.allowed_lateness(), limits)
+}
+
+fn new_absence(time_window_seconds: u64, limits: &StateLimits) -> AbsenceTracker {
+    AbsenceTracker::new(Duration::seconds(time_window_seconds as i64), limits)
+}
+
+// e.g. "ip_address=10.0.0.1, user_id=root"
+fn describe_group(rule: &Rule, key: &GroupKey) -> String {
+    rule.group_fields()
+        .iter()
+        .zip(key)
+        .map(|(field, value)| format!("{}={}", field, value))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl ThreatDetector {
-    pub fn new(rules: Vec<Rule>) -> Self {
+    pub fn new(rules_engine: Arc<RulesEngine>) -> Self {
+        ThreatDetector::with_clock(rules_engine, DetectorClock::EventTime)
+    }
+
+    pub fn with_clock(rules_engine: Arc<RulesEngine>, clock: DetectorClock) -> Self {
         ThreatDetector {
-            rules,
-            brute_force_attempts: HashMap::new(),
-            high_frequency_requests: HashMap::new(),
-            suspicious_ip_behavior: HashMap::new(),
+            rules_engine,
+            clock,
+            limits: StateLimits::default(),
+            windows: HashMap::new(),
+            sequences: HashMap::new(),
+            absences: HashMap::new(),
+            pending_alerts: Vec::new(),
+            latest_event: None,
+            late_entries: 0,
+            untimestamped_entries: 0,
         }
     }
 
-    pub fn detect_threats(&mut self, log_entry: &LogEntry) -> Option<Alert> {
+    /// Bounds the per-group state each rule keeps. Without limits, groups are still forgotten
+    /// once their state can no longer affect detection.
+    pub fn with_limits(mut self, limits: StateLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Groups tracked across all rules, how many were expired or evicted so far, and how many
+    /// entries were skipped for being late or untimestamped.
+    pub fn stats(&self) -> DetectorStats {
+        let mut stats = DetectorStats { late_entries: self.late_entries, untimestamped_entries: self.untimestamped_entries, ..DetectorStats::default() };
+        self.windows.values().for_each(|aggregator| stats.add(aggregator.stats()));
+        self.sequences.values().for_each(|tracker| stats.add(tracker.stats()));
+        self.absences.values().for_each(|tracker| stats.add(tracker.stats()));
+        stats
+    }
+
+    pub fn rules_engine(&self) -> &Arc<RulesEngine> {
+        &self.rules_engine
+    }
+
+    /// Feeds one entry to every windowed, sequence and absence rule it matches and returns the
+    /// alerts it completes, in rule order. Absence alerts go to `take_pending_alerts` instead.
+    pub fn detect_threats(&mut self, log_entry: &LogEntry) -> Vec<Alert> {
         let mut generated_alerts: Vec<Alert> = Vec::new();
 
-        // Clone rules to iterate over them, allowing mutable access to self within the loop
-        let rules_clone = self.rules.clone();
-
-        for rule in rules_clone {
-            match rule.rule_type {
-                RuleType::BruteForce => {
-                    if let Some(alert) = self.check_brute_force(log_entry, &rule) {
-                        generated_alerts.push(alert);
-                    }
-                }
-                RuleType::HighFrequencyRequest => {
-                    if let Some(alert) = self.check_high_frequency_request(log_entry, &rule) {
-                        generated_alerts.push(alert);
-                    }
-                }
-                RuleType::SuspiciousIp => {
-                    if let Some(alert) = self.check_suspicious_ip_behavior(log_entry, &rule) {
-                        generated_alerts.push(alert);
-                    }
-                }
-                RuleType::Custom(_) => {
-                    // Custom rules logic would go here
-                }
+        // Hold our own handle on the engine, allowing mutable access to self within the loop
+        let rules_engine = Arc::clone(&self.rules_engine);
+
+        if let Some(timestamp) = log_entry.timestamp {
+            let latest = self.latest_event.map_or(timestamp, |latest| latest.max(timestamp));
+            self.latest_event = Some(latest);
+            // On event time, silence is measured up to the newest entry before this one can count as a heartbeat
+            if self.clock == DetectorClock::EventTime {
+                self.check_absences(latest);
             }
         }
-        generated_alerts.into_iter().next()
-    }
 
-    fn check_brute_force(&mut self, log_entry: &LogEntry, rule: &Rule) -> Option<Alert> {
-        if log_entry.event_type.as_deref() == Some("login_failed") {
-            if let (Some(ip_address), Some(user_id), Some(time_window_seconds), Some(threshold)) = (&log_entry.ip_address, &log_entry.user_id, rule.time_window_seconds, rule.threshold) {
-                let key = (ip_address.clone(), user_id.clone());
-                let (attempts, last_attempt_time) = self.brute_force_attempts.entry(key.clone()).or_insert((0, log_entry.timestamp.unwrap_or_else(|| Utc::now())));
-
-                if log_entry.timestamp.unwrap_or_else(|| Utc::now()) - *last_attempt_time < Duration::seconds(time_window_seconds as i64) {
-                    *attempts += 1;
-                } else {
-                    *attempts = 1;
-                }
-                *last_attempt_time = log_entry.timestamp.unwrap_or_else(|| Utc::now());
-
-                if *attempts >= threshold {
-                    self.brute_force_attempts.remove(&key);
-                    return Some(Alert {
-                        id: Uuid::new_v4().to_string(),
-                        timestamp: log_entry.timestamp.unwrap_or_else(|| Utc::now()),
-                        alert_type: AlertType::BruteForce,
-                        description: format!("Brute-force attempt detected from IP {} for user {}", ip_address, user_id),
-                        log_entry_sample: Some(log_entry.clone()),
-                    });
-                }
+        let (mut late, mut untimestamped) = (false, false);
+        for rule in rules_engine.matching_windowed_rules(log_entry) {
+            // Wall-clock heartbeats are timed on arrival
+            if let (RuleType::Absence, DetectorClock::WallClock) = (&rule.rule_type, self.clock) {
+                self.record_presence(log_entry, rule, Utc::now());
+                continue;
+            }
+            let Some(time) = log_entry.timestamp else {
+                untimestamped = true;
+                continue;
+            };
+            if self.is_late(rule, time) {
+                late = true;
+                continue;
+            }
+            let alert = match rule.rule_type {
+                RuleType::Sequence => self.check_sequence(&rules_engine, log_entry, rule, time),
+                RuleType::Absence => {
+                    self.record_presence(log_entry, rule, time);
+                    None
+                },
+                _ => self.check_window(log_entry, rule, time),
+            };
+            if let Some(alert) = alert {
+                generated_alerts.push(alert);
             }
         }
-        None
+        self.late_entries += late as u64;
+        self.untimestamped_entries += untimestamped as u64;
+        generated_alerts
+    }
+
+    // Whether `time` is behind the rule's watermark.
+    fn is_late(&self, rule: &Rule, time: DateTime<Utc>) -> bool {
+        self.latest_event.is_some_and(|latest| time < latest - rule.allowed_lateness())
     }
 
-    fn check_high_frequency_request(&mut self, log_entry: &LogEntry, rule: &Rule) -> Option<Alert> {
-        if let (Some(ip_address), Some(time_window_seconds), Some(threshold)) = (&log_entry.ip_address, rule.time_window_seconds, rule.threshold) {
-            let key = ip_address.clone();
-            let (count, last_request_time) = self.high_frequency_requests.entry(key.clone()).or_insert((0, log_entry.timestamp.unwrap_or_else(|| Utc::now())));
+    // Adds the entry to the rule's window for its group and fires once the aggregate reaches
+    // the threshold, starting the group's window over.
+    fn check_window(&mut self, log_entry: &LogEntry, rule: &Rule, time: DateTime<Utc>) -> Option<Alert> {
+        let (Some(key), Some(time_window_seconds), Some(threshold)) = (group_key(log_entry, rule), rule.time_window_seconds, rule.threshold) else {
+            return None;
+        };
+        let aggregator = self.windows
+            .entry(rule.id.clone())
+            .or_insert_with(|| new_window(rule, time_window_seconds, &self.limits));
 
-            if log_entry.timestamp.unwrap_or_else(|| Utc::now()) - *last_request_time < Duration::seconds(time_window_seconds as i64) {
-                *count += 1;
-            } else {
-                *count = 1;
-            }
-            *last_request_time = log_entry.timestamp.unwrap_or_else(|| Utc::now());
+        let value = aggregator.value_field().and_then(|field| log_entry.field(field));
+        let aggregate = aggregator.add(key.clone(), time, value.as_deref());
+        if aggregate < threshold as f64 {
+            return None;
+        }
+        aggregator.reset(key.clone(), time);
+
+        let group = describe_group(rule, &key);
+        let (alert_type, description) = match &rule.rule_type {
+            RuleType::BruteForce => (AlertType::BruteForce, format!("Brute-force attempt detected for {}", group)),
+            RuleType::HighFrequencyRequest => (AlertType::HighFrequencyRequest, format!("High-frequency requests detected for {}", group)),
+            RuleType::SuspiciousIp => (AlertType::SuspiciousIp, format!("Suspicious IP behavior detected for {}: multiple event types", group)),
+            RuleType::DistinctCount { field } => (rule.alert_type.clone(), format!("Rule '{}' triggered for {}: {} distinct {} values", rule.name, group, aggregate, field)),
+            RuleType::Sequence | RuleType::Absence | RuleType::Custom(_) => (rule.alert_type.clone(), format!("Rule '{}' triggered for {}: {}", rule.name, group, rule.description)),
+        };
+        Some(Alert {
+            id: Uuid::new_v4().to_string(),
+            rule_id: rule.id.clone(),
+            timestamp: time,
+            alert_type,
+            description,
+            log_entry_sample: Some(log_entry.clone()),
+            related_entries: Vec::new(),
+        })
+    }
+
+    // Advances the entry's group through the rule's steps and fires with the whole sequence once
+    // the last step completes within `time_window_seconds`.
+    fn check_sequence(&mut self, rules_engine: &RulesEngine, log_entry: &LogEntry, rule: &Rule, time: DateTime<Utc>) -> Option<Alert> {
+        let (Some(key), Some(time_window_seconds)) = (group_key(log_entry, rule), rule.time_window_seconds) else {
+            return None;
+        };
+        let matched_steps = rules_engine.matching_sequence_steps(rule, log_entry);
+        if matched_steps.is_empty() {
+            return None;
+        }
+        let tracker = self.sequences
+            .entry(rule.id.clone())
+            .or_insert_with(|| new_sequence(rule, time_window_seconds, &self.limits));
 
-            if *count >= threshold {
-                self.high_frequency_requests.remove(&key);
-                return Some(Alert {
+        let entries = tracker.add(key.clone(), time, log_entry, &matched_steps)?;
+        Some(Alert {
+            id: Uuid::new_v4().to_string(),
+            rule_id: rule.id.clone(),
+            timestamp: time,
+            alert_type: rule.alert_type.clone(),
+            description: format!("Sequence '{}' completed for {} ({} events): {}", rule.name, describe_group(rule, &key), entries.len(), rule.description),
+            log_entry_sample: Some(log_entry.clone()),
+            related_entries: entries,
+        })
+    }
+
+    // Counts the entry as its group's latest heartbeat, seen at `time`.
+    fn record_presence(&mut self, log_entry: &LogEntry, rule: &Rule, time: DateTime<Utc>) {
+        let (Some(key), Some(time_window_seconds)) = (group_key(log_entry, rule), rule.time_window_seconds) else {
+            return;
+        };
+        self.absences
+            .entry(rule.id.clone())
+            .or_insert_with(|| new_absence(time_window_seconds, &self.limits))
+            .seen(key, time, log_entry);
+    }
+
+    /// Raises an alert, into the pending alerts, for every group of an absence rule that has been
+    /// silent for longer than the rule's interval as of `now`. On event time, `now` is the newest
+    /// entry time and silence is judged up to the rule's watermark, so late heartbeats still count.
+    pub fn check_absences(&mut self, now: DateTime<Utc>) {
+        for (rule_id, tracker) in &mut self.absences {
+            let Some(rule) = self.rules_engine.rules().iter().find(|rule| &rule.id == rule_id) else { continue };
+            let horizon = match self.clock {
+                DetectorClock::EventTime => now - rule.allowed_lateness(),
+                DetectorClock::WallClock => now,
+            };
+            for silence in tracker.overdue(horizon) {
+                self.pending_alerts.push(Alert {
                     id: Uuid::new_v4().to_string(),
-                    timestamp: log_entry.timestamp.unwrap_or_else(|| Utc::now()),
-                    alert_type: AlertType::HighFrequencyRequest,
-                    description: format!("High-frequency requests detected from IP {}", ip_address),
-                    log_entry_sample: Some(log_entry.clone()),
+                    rule_id: rule.id.clone(),
+                    timestamp: silence.deadline,
+                    alert_type: rule.alert_type.clone(),
+                    description: format!("Rule '{}' triggered for {}: no expected event since {} (expected every {}s): {}",
+                        rule.name, describe_group(rule, &silence.group), silence.last_seen.to_rfc3339(), rule.time_window_seconds.unwrap_or_default(), rule.description),
+                    log_entry_sample: Some(silence.log_entry),
+                    related_entries: Vec::new(),
                 });
             }
         }
-        None
     }
 
-    fn check_suspicious_ip_behavior(&mut self, log_entry: &LogEntry, rule: &Rule) -> Option<Alert> {
-        if let (Some(ip_address), Some(time_window_seconds), Some(threshold)) = (&log_entry.ip_address, rule.time_window_seconds, rule.threshold) {
-            let key = ip_address.clone();
-            let (event_counts, last_event_time) = self.suspicious_ip_behavior.entry(key.clone()).or_insert_with(|| (HashMap::new(), log_entry.timestamp.unwrap_or_else(|| Utc::now())));
+    /// Drains the absence alerts raised so far.
+    pub fn take_pending_alerts(&mut self) -> Vec<Alert> {
+        std::mem::take(&mut self.pending_alerts)
+    }
 
-            if log_entry.timestamp.unwrap_or_else(|| Utc::now()) - *last_event_time < Duration::seconds(time_window_seconds as i64) {
-                if let Some(event_type) = log_entry.event_type.clone() {
-                    *event_counts.entry(event_type).or_insert(0) += 1;
-                }
+    /// Copies every rule's state, tagged with the rule's fingerprint.
+    pub fn snapshot(&self) -> DetectorSnapshot {
+        let mut rules = Vec::new();
+        for rule in self.rules_engine.rules() {
+            let state = if let Some(aggregator) = self.windows.get(&rule.id) {
+                RuleState::Window(aggregator.snapshot())
+            } else if let Some(tracker) = self.sequences.get(&rule.id) {
+                RuleState::Sequence(tracker.snapshot())
+            } else if let Some(tracker) = self.absences.get(&rule.id) {
+                RuleState::Absence(tracker.snapshot())
             } else {
-                event_counts.clear();
-                if let Some(event_type_str) = log_entry.event_type.clone() {
-                    *event_counts.entry(event_type_str).or_insert(0) = 1;
-                }
-            }
-            *last_event_time = log_entry.timestamp.unwrap_or_else(|| Utc::now());
+                continue;
+            };
+            rules.push(RuleSnapshot { rule_id: rule.id.clone(), fingerprint: rule_fingerprint(rule), state });
+        }
+        DetectorSnapshot { version: SNAPSHOT_VERSION, taken_at: Utc::now(), latest_event: self.latest_event, rules, pending_alerts: self.pending_alerts.clone() }
+    }
 
-            // Example: If an IP has more than 'threshold' unique event types in the time window
-            if event_counts.len() >= threshold {
-                self.suspicious_ip_behavior.remove(&key);
-                return Some(Alert {
-                    id: Uuid::new_v4().to_string(),
-                    timestamp: log_entry.timestamp.unwrap_or_else(|| Utc::now()),
-                    alert_type: AlertType::SuspiciousIp,
-                    description: format!("Suspicious IP behavior detected from IP {}: multiple event types", ip_address),
-                    log_entry_sample: Some(log_entry.clone()),
-                });
+    /// Loads the state of every rule that is unchanged since `snapshot` was taken; the state of
+    /// rules that were edited or removed is dropped. Returns how many rules were restored and dropped.
+    pub fn restore(&mut self, snapshot: DetectorSnapshot) -> (usize, usize) {
+        let (mut restored, mut dropped) = (0, 0);
+        for saved in snapshot.rules {
+            let current = self.rules_engine.rules()
+                .iter()
+                .find(|rule| rule.id == saved.rule_id && rule.enabled && rule_fingerprint(rule) == saved.fingerprint)
+                .and_then(|rule| Some((rule, rule.time_window_seconds?)));
+            let Some((rule, time_window_seconds)) = current else {
+                dropped += 1;
+                continue;
+            };
+            match saved.state {
+                RuleState::Window(groups) => {
+                    let mut aggregator = new_window(rule, time_window_seconds, &self.limits);
+                    aggregator.restore(groups);
+                    self.windows.insert(rule.id.clone(), aggregator);
+                },
+                RuleState::Sequence(groups) => {
+                    let mut tracker = new_sequence(rule, time_window_seconds, &self.limits);
+                    tracker.restore(groups);
+                    self.sequences.insert(rule.id.clone(), tracker);
+                },
+                RuleState::Absence(groups) => {
+                    let mut tracker = new_absence(time_window_seconds, &self.limits);
+                    tracker.restore(groups);
+                    self.absences.insert(rule.id.clone(), tracker);
+                },
             }
+            restored += 1;
         }
-        None
+        self.pending_alerts.extend(snapshot.pending_alerts);
+        self.latest_event = self.latest_event.max(snapshot.latest_event);
+        (restored, dropped)
     }
 }
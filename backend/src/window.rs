@@ -0,0 +1,305 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use crate::group_store::{GroupSnapshot, GroupStore, StateLimits};
+use crate::models::{Aggregation, DetectorStats, WindowKind};
+
+/// Values of a rule's group-by fields for one entry.
+pub type GroupKey = Vec<String>;
+
+// One group's window: the events it currently holds, in time order, plus running aggregates.
+// Distinct counts keep one entry per value instead of every event, so a group's state is bounded
+// by the number of different values in its window (at most the rule's threshold, since the group
+// starts over when it fires) however many entries repeat them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GroupWindow {
+    // Count and Sum only
+    events: VecDeque<(DateTime<Utc>, Option<String>)>,
+    // Latest event time seen; sliding windows end here
+    latest: Option<DateTime<Utc>>,
+    sum: f64,
+    // Value hash -> latest time it was seen, and the same pairs ordered by time for eviction
+    distinct: HashMap<u64, DateTime<Utc>>,
+    distinct_by_time: BTreeSet<(DateTime<Utc>, u64)>,
+}
+
+// Distinct values are persisted in snapshots by hash, so the hash must not change between builds
+// (which `DefaultHasher` doesn't promise): the first 8 bytes of the value's SHA-256.
+fn value_hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 digests are 32 bytes"))
+}
+
+// What a value adds to a Sum: unparseable and non-finite values ("NaN", "inf") count as zero, since
+// one of them would otherwise poison the running sum for good.
+fn summand(value: &str) -> f64 {
+    value.parse::<f64>().ok().filter(|number| number.is_finite()).unwrap_or(0.0)
+}
+
+impl GroupWindow {
+    fn insert(&mut self, time: DateTime<Utc>, value: Option<&str>, aggregation: &Aggregation) {
+        if let Aggregation::DistinctCount { .. } = aggregation {
+            if let Some(value) = value {
+                self.insert_distinct(time, value_hash(value));
+            }
+            return;
+        }
+        if let (Aggregation::Sum { .. }, Some(value)) = (aggregation, value) {
+            self.sum += summand(value);
+        }
+        // Keep time order so eviction from the front stays correct for out-of-order arrivals
+        let position = self.events.partition_point(|(event_time, _)| *event_time <= time);
+        self.events.insert(position, (time, value.map(str::to_string)));
+    }
+
+    fn insert_distinct(&mut self, time: DateTime<Utc>, hash: u64) {
+        if let Some(&seen) = self.distinct.get(&hash) {
+            if seen >= time {
+                return;
+            }
+            self.distinct_by_time.remove(&(seen, hash));
+        }
+        self.distinct.insert(hash, time);
+        self.distinct_by_time.insert((time, hash));
+    }
+
+    // Drops everything at or before `window_start`.
+    fn evict_until(&mut self, window_start: DateTime<Utc>, aggregation: &Aggregation) {
+        while self.events.front().is_some_and(|(event_time, _)| *event_time <= window_start) {
+            if let Some((_, Some(value))) = self.events.pop_front() {
+                if let Aggregation::Sum { .. } = aggregation {
+                    self.sum -= summand(&value);
+                }
+            }
+        }
+        while let Some(&(time, hash)) = self.distinct_by_time.first() {
+            if time > window_start {
+                break;
+            }
+            self.distinct_by_time.pop_first();
+            self.distinct.remove(&hash);
+        }
+    }
+
+    fn value(&self, aggregation: &Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Count => self.events.len() as f64,
+            Aggregation::DistinctCount { .. } => self.distinct.len() as f64,
+            Aggregation::Sum { .. } => self.sum,
+        }
+    }
+}
+
+/// Per-group windowed aggregation for one rule.
+///
+/// Sliding windows hold the events in `(latest - width, latest]`, where `latest` is the newest
+/// event time seen for the group; events that fall before the window are dropped. Tumbling windows
+/// hold the events of each epoch-aligned bucket of `width`, and every bucket a group touches is
+/// tracked on its own, so a late event still lands in the bucket it belongs to. Events later than
+/// the rule's `lateness` are kept out by the caller.
+#[derive(Debug)]
+pub struct WindowAggregator {
+    kind: WindowKind,
+    width: Duration,
+    aggregation: Aggregation,
+    // Per group, or per group and bucket for tumbling windows
+    groups: GroupStore<GroupWindow>,
+}
+
+impl WindowAggregator {
+    pub fn new(kind: WindowKind, width: Duration, aggregation: Aggregation, lateness: Duration, limits: &StateLimits) -> Self {
+        // A group's window is empty once `width` has passed since its latest event, and late
+        // events can't reach it once `lateness` more has passed
+        let groups = GroupStore::new(limits.max_keys, limits.idle_timeout(Some(width + lateness)));
+        WindowAggregator { kind, width, aggregation, groups }
+    }
+
+    /// The field whose values the aggregation reads, if any.
+    pub fn value_field(&self) -> Option<&str> {
+        match &self.aggregation {
+            Aggregation::Count => None,
+            Aggregation::DistinctCount { field } | Aggregation::Sum { field } => Some(field),
+        }
+    }
+
+    /// Adds an event and returns the group's aggregate over its window afterwards.
+    pub fn add(&mut self, group: GroupKey, time: DateTime<Utc>, value: Option<&str>) -> f64 {
+        let key = self.window_key(group, time);
+        let window = self.groups.entry(key, time, GroupWindow::default);
+        if let WindowKind::Sliding = self.kind {
+            let latest = window.latest.map_or(time, |latest| latest.max(time));
+            window.latest = Some(latest);
+            let window_start = latest - self.width;
+            if time > window_start {
+                window.insert(time, value, &self.aggregation);
+            }
+            window.evict_until(window_start, &self.aggregation);
+        } else {
+            window.insert(time, value, &self.aggregation);
+        }
+        window.value(&self.aggregation)
+    }
+
+    /// Forgets the group's window holding `time`, e.g. after its rule fired.
+    pub fn reset(&mut self, group: GroupKey, time: DateTime<Utc>) {
+        let key = self.window_key(group, time);
+        self.groups.remove(&key);
+    }
+
+    // Tumbling windows key each bucket by its start, in epoch milliseconds, after the group's values.
+    fn window_key(&self, mut group: GroupKey, time: DateTime<Utc>) -> GroupKey {
+        if let WindowKind::Tumbling = self.kind {
+            group.push(bucket_start(time, self.width).timestamp_millis().to_string());
+        }
+        group
+    }
+
+    pub fn stats(&self) -> DetectorStats {
+        self.groups.stats()
+    }
+
+    pub fn snapshot(&self) -> Vec<GroupSnapshot<GroupWindow>> {
+        self.groups.snapshot()
+    }
+
+    pub fn restore(&mut self, groups: Vec<GroupSnapshot<GroupWindow>>) {
+        self.groups.restore(groups);
+    }
+}
+
+fn bucket_start(time: DateTime<Utc>, width: Duration) -> DateTime<Utc> {
+    let width_ms = width.num_milliseconds().max(1);
+    let start_ms = time.timestamp_millis().div_euclid(width_ms) * width_ms;
+    DateTime::from_timestamp_millis(start_ms).unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn group(name: &str) -> GroupKey {
+        vec![name.to_string()]
+    }
+
+    fn counts(aggregator: &mut WindowAggregator, times: &[i64]) -> Vec<f64> {
+        times.iter().map(|&t| aggregator.add(group("a"), at(t), None)).collect()
+    }
+
+    #[test]
+    fn sliding_count_does_not_accumulate_a_slow_drip() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        assert_eq!(counts(&mut aggregator, &[0, 50, 100, 150, 200]), vec![1.0, 2.0, 2.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn sliding_count_sees_a_whole_burst() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        assert_eq!(counts(&mut aggregator, &[0, 1, 2, 3, 59, 60, 61]), vec![1.0, 2.0, 3.0, 4.0, 5.0, 5.0, 5.0]);
+    }
+
+    #[test]
+    fn sliding_window_handles_out_of_order_events() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        // 30 arrives late but is still inside the window ending at 80; 10 is already outside it
+        assert_eq!(counts(&mut aggregator, &[0, 80, 30, 10, 85]), vec![1.0, 1.0, 2.0, 2.0, 3.0]);
+        // 30 leaves the window once it ends at 90 or later
+        assert_eq!(counts(&mut aggregator, &[91]), vec![3.0]);
+    }
+
+    #[test]
+    fn tumbling_count_resets_at_bucket_boundaries() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Tumbling, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        // at(0) is 20s into a 60s bucket (1_700_000_000 % 60 == 20), which ends at at(40); the
+        // late event at(5) still counts towards the first bucket
+        assert_eq!(counts(&mut aggregator, &[0, 10, 39, 40, 41, 5]), vec![1.0, 2.0, 3.0, 1.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn groups_are_independent() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        aggregator.add(group("a"), at(0), None);
+        aggregator.add(group("a"), at(1), None);
+        assert_eq!(aggregator.add(group("b"), at(2), None), 1.0);
+        aggregator.reset(group("a"), at(2));
+        assert_eq!(aggregator.add(group("a"), at(3), None), 1.0);
+    }
+
+    #[test]
+    fn distinct_count_forgets_evicted_values() {
+        let field = "event_type".to_string();
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::DistinctCount { field }, Duration::zero(), &StateLimits::default());
+        let events = [(0, "login"), (10, "login"), (20, "scan"), (30, "upload"), (65, "scan"), (75, "scan")];
+        let values: Vec<f64> = events.iter().map(|&(t, value)| aggregator.add(group("a"), at(t), Some(value))).collect();
+        assert_eq!(values, vec![1.0, 1.0, 2.0, 3.0, 3.0, 2.0]);
+        assert_eq!(aggregator.value_field(), Some("event_type"));
+    }
+
+    #[test]
+    fn distinct_count_keeps_one_entry_per_value() {
+        let field = "user_id".to_string();
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::DistinctCount { field }, Duration::zero(), &StateLimits::default());
+        for t in 0..1000 {
+            let user = if t % 2 == 0 { "root" } else { "admin" };
+            aggregator.add(group("a"), at(t / 100), Some(user));
+        }
+        let window = aggregator.groups.get_mut(&group("a")).unwrap();
+        assert_eq!((window.distinct.len(), window.distinct_by_time.len(), window.events.len()), (2, 2, 0));
+        // Values leave the window with their latest sighting
+        assert_eq!(aggregator.add(group("a"), at(65), Some("guest")), 3.0);
+        assert_eq!(aggregator.add(group("a"), at(75), Some("guest")), 1.0);
+    }
+
+    #[test]
+    fn key_budget_evicts_the_least_recently_updated_group() {
+        let limits = StateLimits { max_keys: Some(2), idle_timeout: None };
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &limits);
+        aggregator.add(group("a"), at(0), None);
+        aggregator.add(group("b"), at(1), None);
+        aggregator.add(group("a"), at(2), None);
+        aggregator.add(group("c"), at(3), None);
+        assert_eq!(aggregator.add(group("a"), at(4), None), 3.0);
+        assert_eq!(aggregator.add(group("b"), at(5), None), 1.0);
+        assert_eq!(aggregator.stats(), DetectorStats { tracked_keys: 2, expired_keys: 0, evicted_keys: 2, ..DetectorStats::default() });
+    }
+
+    #[test]
+    fn idle_groups_expire_once_their_window_is_empty() {
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &StateLimits::default());
+        aggregator.add(group("a"), at(0), None);
+        aggregator.add(group("b"), at(30), None);
+        aggregator.add(group("c"), at(61), None);
+        assert_eq!(aggregator.stats(), DetectorStats { tracked_keys: 2, expired_keys: 1, evicted_keys: 0, ..DetectorStats::default() });
+
+        let limits = StateLimits { max_keys: None, idle_timeout: Some(Duration::seconds(10)) };
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::zero(), &limits);
+        aggregator.add(group("a"), at(0), None);
+        assert_eq!(aggregator.add(group("a"), at(11), None), 1.0);
+    }
+
+    #[test]
+    fn lateness_keeps_idle_groups_around_for_late_events() {
+        let mut late_counts = Vec::new();
+        for lateness in [0, 30] {
+            let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Count, Duration::seconds(lateness), &StateLimits::default());
+            aggregator.add(group("a"), at(0), None);
+            aggregator.add(group("b"), at(80), None);
+            late_counts.push(aggregator.add(group("a"), at(20), None));
+        }
+        assert_eq!(late_counts, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn sum_adds_numeric_values_and_subtracts_evicted_ones() {
+        let field = "bytes".to_string();
+        let mut aggregator = WindowAggregator::new(WindowKind::Sliding, Duration::seconds(60), Aggregation::Sum { field }, Duration::zero(), &StateLimits::default());
+        let events = [(0, Some("100")), (10, Some("250.5")), (20, None), (30, Some("n/a")), (40, Some("NaN")), (50, Some("inf")), (70, Some("50")), (125, Some("1"))];
+        let values: Vec<f64> = events.iter().map(|&(t, value)| aggregator.add(group("a"), at(t), value)).collect();
+        assert_eq!(values, vec![100.0, 350.5, 350.5, 350.5, 350.5, 350.5, 50.0, 51.0]);
+    }
+}